@@ -0,0 +1,115 @@
+// The runtime errors, shared by the compiler, which passes their codes to
+// snek_error, and the runtime, which reports them
+
+// The operations that check the types of their operands, which the
+// compiled code passes to snek_error by index
+pub const OPERATIONS: [&str; 12] =
+    ["+", "-", "*", "<", "<=", ">", ">=", "add1", "sub1", "tup-get", "tup-set!", "tup-len"];
+
+pub fn operation(name: &str) -> i64 {
+    match OPERATIONS.iter().position(|op| *op == name) {
+        Some(i) => i as i64,
+        None => panic!("Not an operation that checks its operands: {}", name),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SnekError {
+    InvalidEqual = 1,
+    InvalidArith = 2,
+    Overflow = 3,
+    NotATuple = 4,
+    OutOfMemory = 5,
+    StackOverflow = 6,
+    IndexOutOfBounds = 7,
+    NotAFunction = 8,
+    ArityMismatch = 9,
+}
+
+impl SnekError {
+    pub const ALL: [SnekError; 9] = [
+        SnekError::InvalidEqual,
+        SnekError::InvalidArith,
+        SnekError::Overflow,
+        SnekError::NotATuple,
+        SnekError::OutOfMemory,
+        SnekError::StackOverflow,
+        SnekError::IndexOutOfBounds,
+        SnekError::NotAFunction,
+        SnekError::ArityMismatch,
+    ];
+
+    pub fn code(self) -> i64 {
+        self as i64
+    }
+
+    pub fn from_code(code: i64) -> Option<SnekError> {
+        SnekError::ALL.iter().copied().find(|e| e.code() == code)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SnekError::InvalidEqual => "invalid_equal",
+            SnekError::InvalidArith => "invalid_arith",
+            SnekError::Overflow => "overflow",
+            SnekError::NotATuple => "not_a_tuple",
+            SnekError::OutOfMemory => "out_of_memory",
+            SnekError::StackOverflow => "stack_overflow",
+            SnekError::IndexOutOfBounds => "index_out_of_bounds",
+            SnekError::NotAFunction => "not_a_function",
+            SnekError::ArityMismatch => "arity_mismatch",
+        }
+    }
+
+    // The message, where {0}, {1}... stand for the operands of the error
+    pub fn template(self) -> &'static str {
+        match self {
+            SnekError::InvalidEqual => "= expected operands of the same type but got {0} and {1}",
+            SnekError::InvalidArith => "{0} expected a number but got {1}",
+            SnekError::Overflow => "overflow",
+            SnekError::NotATuple => "{0} expected a tuple but got {1}",
+            SnekError::OutOfMemory => "out of memory",
+            SnekError::StackOverflow => "stack overflow",
+            SnekError::IndexOutOfBounds => "index {0} out of bounds for tuple of length {1}",
+            SnekError::NotAFunction => "expected a function but got {0}",
+            SnekError::ArityMismatch => "function expects {0} arguments, but got {1}",
+        }
+    }
+
+    // The operands from what the compiled code passes to snek_error: the
+    // value in RAX and the word in RCX. show formats a value
+    // value and extra must be what the compiled code passed for this error,
+    // so that a tuple or function value points to a live one
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe fn operands(self, value: u64, extra: u64, show: impl Fn(u64) -> String) -> Vec<String> {
+        match self {
+            // The operands of =, in order
+            SnekError::InvalidEqual => vec![show(extra), show(value)],
+            // The operation, and the value it got
+            SnekError::InvalidArith | SnekError::NotATuple => {
+                vec![OPERATIONS[extra as usize].to_string(), show(value)]
+            }
+            // The tuple, and the index
+            SnekError::IndexOutOfBounds => {
+                let len = ((value - 1) as *const u64).read() >> 1;
+                vec![(extra as i64 >> 1).to_string(), len.to_string()]
+            }
+            SnekError::NotAFunction => vec![show(value)],
+            // The function, and the number of arguments it got. A function
+            // value holds its arity after its code
+            SnekError::ArityMismatch => {
+                let arity = ((value - 5) as *const u64).add(2).read() >> 1;
+                vec![arity.to_string(), extra.to_string()]
+            }
+            SnekError::Overflow | SnekError::OutOfMemory | SnekError::StackOverflow => vec![],
+        }
+    }
+
+    pub fn message(self, operands: &[String]) -> String {
+        let mut message = self.template().to_string();
+        for (i, operand) in operands.iter().enumerate() {
+            message = message.replace(&format!("{{{}}}", i), operand);
+        }
+        message
+    }
+}
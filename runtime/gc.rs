@@ -0,0 +1,106 @@
+// The garbage collector, shared by the runtime and by snek run --jit
+//
+// The heap holds tuples one after another: a header with the size shifted
+// left by 1, then the elements. A tuple value is its address plus 1.
+// Function values are laid out like tuples, and are their address plus 5.
+// When the compiled code calls the collector every live value is in a stack
+// slot, so the roots are the words between the top of the stack and the rsp
+// our_code_starts_here started with. Slots can hold stale values, so any
+// word that points to the start of a tuple is taken as a root: at worst that
+// keeps some garbage alive.
+
+use std::ptr;
+
+// Tuples and function values, whose tags both end in 01
+fn is_heap_value(v: u64) -> bool {
+    v & 3 == 1
+}
+
+unsafe fn tuple_size(t: *const u64) -> usize {
+    (t.read() >> 1) as usize
+}
+
+// The offset in words of each tuple between heap_start and heap_ptr
+unsafe fn tuples(heap_start: *mut u64, heap_ptr: *mut u64) -> Vec<usize> {
+    let mut starts = vec![];
+    let mut t = heap_start;
+    while t < heap_ptr {
+        starts.push(t.offset_from(heap_start) as usize);
+        t = t.add(tuple_size(t) + 1);
+    }
+    starts
+}
+
+// Mark-compact: marks the tuples reachable from the stack, following cycles
+// only once, then slides them to the start of the heap in the order they
+// were allocated, updating the stack and the tuples that point to them
+// Returns the new heap pointer
+pub unsafe fn collect(
+    heap_start: *mut u64,
+    heap_ptr: *mut u64,
+    stack_top: *mut u64,
+    stack_base: *mut u64,
+) -> *mut u64 {
+    let starts = tuples(heap_start, heap_ptr);
+    // The tuple a value points to, as an index into starts
+    let index = |v: u64| -> Option<usize> {
+        let addr = (v & !7) as usize;
+        if !is_heap_value(v) || addr < heap_start as usize || addr >= heap_ptr as usize {
+            return None;
+        }
+        starts.binary_search(&((addr - heap_start as usize) / 8)).ok()
+    };
+    let roots = stack_base.offset_from(stack_top) as usize;
+
+    let mut marked = vec![false; starts.len()];
+    let mut work: Vec<usize> = (0..roots).filter_map(|i| index(stack_top.add(i).read())).collect();
+    while let Some(i) = work.pop() {
+        if marked[i] {
+            continue;
+        }
+        marked[i] = true;
+        let t = heap_start.add(starts[i]);
+        for j in 1..=tuple_size(t) {
+            if let Some(k) = index(t.add(j).read()) {
+                work.push(k);
+            }
+        }
+    }
+
+    let mut forward = vec![0; starts.len()];
+    let mut next = 0;
+    for (i, &start) in starts.iter().enumerate() {
+        if marked[i] {
+            forward[i] = next;
+            next += tuple_size(heap_start.add(start)) + 1;
+        }
+    }
+    // Moved values keep their tag
+    let update = |slot: *mut u64| {
+        let v = slot.read();
+        if let Some(i) = index(v) {
+            slot.write(heap_start.add(forward[i]) as u64 | (v & 7));
+        }
+    };
+    for i in 0..roots {
+        update(stack_top.add(i));
+    }
+    for (i, &start) in starts.iter().enumerate() {
+        if marked[i] {
+            let t = heap_start.add(start);
+            for j in 1..=tuple_size(t) {
+                update(t.add(j));
+            }
+        }
+    }
+
+    // Tuples only move down, so copying them in order does not overwrite
+    // any that are still to be moved
+    for (i, &start) in starts.iter().enumerate() {
+        if marked[i] {
+            let t = heap_start.add(start);
+            ptr::copy(t, heap_start.add(forward[i]), tuple_size(t) + 1);
+        }
+    }
+    heap_start.add(next)
+}
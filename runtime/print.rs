@@ -0,0 +1,31 @@
+// Formats snek values, shared by the runtime and by snek run --jit
+
+use std::collections::HashSet;
+
+const TRUE: i64 = 7;
+const FALSE: i64 = 3;
+const NIL: i64 = 1;
+
+// seen holds the tuples being formatted, so that a cycle prints as (...)
+pub unsafe fn snek_str(val: i64, seen: &mut HashSet<i64>) -> String {
+    if val & 1 == 0 {
+        format!("{}", val >> 1)
+    } else if val == TRUE {
+        "true".to_string()
+    } else if val == FALSE {
+        "false".to_string()
+    } else if val == NIL {
+        "nil".to_string()
+    } else if val & 7 == 5 {
+        "<function>".to_string()
+    } else if seen.contains(&val) {
+        "(...)".to_string()
+    } else {
+        seen.insert(val);
+        let addr = (val - 1) as *const u64;
+        let size = (addr.read() >> 1) as usize;
+        let elems: Vec<String> = (0..size).map(|i| snek_str(addr.add(i + 1).read() as i64, seen)).collect();
+        seen.remove(&val);
+        format!("({})", elems.join(", "))
+    }
+}
@@ -1,10 +1,11 @@
-use std::{collections::HashSet, env};
+use std::{collections::HashSet, env, ffi::CStr, os::raw::c_char, ptr, thread};
 
-type SnekVal = i64;
+mod errors;
+mod gc;
+mod print;
 
-const TRUE: SnekVal = 7;
-const FALSE: SnekVal = 3;
-const NIL: SnekVal = 1;
+use errors::SnekError;
+use print::snek_str;
 
 #[link(name = "our_code")]
 extern "C" {
@@ -12,24 +13,108 @@ extern "C" {
     // it does not add an underscore in front of the name.
     // Courtesy of Max New (https://maxsnew.com/teaching/eecs-483-fa22/hw_adder_assignment.html)
     #[link_name = "\x01our_code_starts_here"]
-    fn our_code_starts_here(input: u64, memory: *mut u64) -> u64;
+    fn our_code_starts_here(input: u64, memory: *mut u64, memory_end: *mut u64, stack_limit: u64) -> u64;
+
+    // Name of the compiled source file, NUL terminated
+    #[link_name = "\x01snek_source_file"]
+    static SNEK_SOURCE_FILE: c_char;
+
+    // (line, column) pairs indexed by the site id of each runtime check
+    #[link_name = "\x01snek_site_table"]
+    static SNEK_SITE_TABLE: u64;
+
+    // The start, end, first site id and name of each function, with code
+    // addresses as offsets from our_code_starts_here
+    #[link_name = "\x01snek_function_table"]
+    static SNEK_FUNCTION_TABLE: i64;
+
+    // The return address and site id of each call
+    #[link_name = "\x01snek_return_table"]
+    static SNEK_RETURN_TABLE: i64;
+
+    // The names in the function table, NUL terminated
+    #[link_name = "\x01snek_function_names"]
+    static SNEK_FUNCTION_NAMES: c_char;
+}
+
+// Formats the source position of a site id as file:line:col
+unsafe fn site_str(site: i64) -> String {
+    let file = CStr::from_ptr(ptr::addr_of!(SNEK_SOURCE_FILE)).to_string_lossy();
+    let entry = ptr::addr_of!(SNEK_SITE_TABLE).add(2 * site as usize);
+    format!("{}:{}:{}", file, entry.read(), entry.add(1).read())
+}
+
+// The frames a backtrace shows at most
+const MAX_FRAMES: usize = 100;
+
+// The entries of a table that starts with its number of entries
+unsafe fn table(start: *const i64, width: usize) -> Vec<&'static [i64]> {
+    let n = start.read() as usize;
+    (0..n).map(|i| std::slice::from_raw_parts(start.add(1 + i * width), width)).collect()
+}
+
+// Prints each function on the stack, innermost first, with where it was
+// when the error happened, following the frames from the one in frame
+// The saved rbp of a frame is at frame, and its return address above it
+unsafe fn print_backtrace(site: i64, frame: *const i64) {
+    let functions = table(ptr::addr_of!(SNEK_FUNCTION_TABLE), 4);
+    let returns = table(ptr::addr_of!(SNEK_RETURN_TABLE), 2);
+    let code = our_code_starts_here as usize as i64;
+    // The error happened in the function its site belongs to
+    let mut function = functions.iter().rev().find(|f| f[2] <= site);
+    let (mut site, mut frame) = (site, frame);
+    for _ in 0..MAX_FRAMES {
+        let Some(f) = function else { return };
+        let name = CStr::from_ptr(ptr::addr_of!(SNEK_FUNCTION_NAMES).add(f[3] as usize));
+        eprintln!("  in {} at {}", name.to_string_lossy(), site_str(site));
+        // main returns to the runtime, which is not in the return table
+        let ret = frame.add(1).read() - code;
+        let Some(call) = returns.iter().find(|r| r[0] == ret) else { return };
+        site = call[1];
+        function = functions.iter().find(|f| f[0] <= ret && ret < f[1]);
+        frame = frame.read() as *const i64;
+    }
+    eprintln!("  ...");
 }
 
 #[no_mangle]
 #[export_name = "\x01snek_error"]
-pub extern "C" fn snek_error(errcode: i64) {
-    eprint!("error {}: ", errcode);
-    // TODO: move error code defs to a separate file
-    match errcode {
-        1 => eprintln!("invalid argument for ="),
-        2 => eprintln!("invalid argument for arithmetic op"),
-        3 => eprintln!("overflow"),
-        4 => eprintln!("tuple value expected"),
-        _ => eprintln!("an error ocurred"),
+// value and extra are only set for the errors that report them
+// frame is the rbp of the code that failed
+pub extern "C" fn snek_error(errcode: i64, site: i64, value: i64, extra: i64, frame: *const i64) {
+    let message = match SnekError::from_code(errcode) {
+        Some(error) => unsafe {
+            let show = |v: u64| snek_str(v as i64, &mut HashSet::new());
+            error.message(&error.operands(value as u64, extra as u64, show))
+        },
+        None => "an error ocurred".to_string(),
+    };
+    eprintln!("error {} at {}: {}", errcode, unsafe { site_str(site) }, message);
+    if env::var("SNEK_BACKTRACE").map_or(false, |v| v == "1") {
+        unsafe { print_backtrace(site, frame) };
     }
     std::process::exit(1);
 }
 
+// Sizes in bytes, unless set with --heap-size and --stack-size or with
+// SNEK_HEAP_SIZE and SNEK_STACK_SIZE
+const HEAP_SIZE: usize = 8 * 1000000;
+const STACK_SIZE: usize = 8 << 20;
+
+// The part of the stack left for the runtime functions when the compiled
+// code reports a stack overflow
+const STACK_MARGIN: usize = 64 << 10;
+
+static mut HEAP_START: *mut u64 = ptr::null_mut();
+
+// Called by the compiled code when the heap is full
+// The compiled code reports the error if it is still full after this
+#[no_mangle]
+#[export_name = "\x01snek_gc"]
+pub unsafe extern "C" fn snek_gc(stack_top: *mut u64, stack_base: *mut u64, heap_ptr: *mut u64) -> *mut u64 {
+    gc::collect(HEAP_START, heap_ptr, stack_top, stack_base)
+}
+
 #[no_mangle]
 #[export_name = "\x01snek_print"]
 // TODO: might need to return an u64 for some reason
@@ -38,30 +123,6 @@ pub unsafe extern "C" fn snek_print(val: i64) -> i64 {
     return val;
 }
 
-#[no_mangle]
-#[export_name = "\x01snek_str"]
-unsafe fn snek_str(val: i64, seen: &mut HashSet<i64>) -> String {
-    if val & 1 == 0 { format!("{}", val >> 1)}
-    else if val == FALSE { format!("false") }
-    else if val == TRUE { format!("true") }
-    else if val == NIL { format!("nil") }
-    else if seen.contains(&val) { format!("(...)") }
-    else if val & 1 == 1 {
-        seen.insert(val);
-        let mut addr = (val - 1) as *mut u64;
-        let size = (addr.read() as usize) >> 1;
-        let mut res = format!("(");
-        for i in 0..size {
-            let elem = addr.add(i + 1).read() as i64;
-            res = format!("{}{}", res, snek_str(elem, seen));
-            if i < size - 1 { res = format!("{}, ", res); }
-        }
-        seen.remove(&val);
-        format!("{})", res)
-    } 
-    else { format!("unexpected value: {}", val) }
-}
-
 fn parse_input(input: &str) -> u64 {
     if input == "true" { 7 }
     else if input == "false" { 3 }
@@ -73,14 +134,66 @@ fn parse_input(input: &str) -> u64 {
 }
 
 
+// A size in bytes, with an optional k, m or g suffix, that must be more
+// than min
+fn parse_size(name: &str, size: &str, min: usize) -> usize {
+    let (digits, unit) = match size.char_indices().last() {
+        Some((i, 'k')) | Some((i, 'K')) => (&size[..i], 1 << 10),
+        Some((i, 'm')) | Some((i, 'M')) => (&size[..i], 1 << 20),
+        Some((i, 'g')) | Some((i, 'G')) => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+    match digits.parse::<usize>().ok().and_then(|n| n.checked_mul(unit)) {
+        Some(n) if n > min => n,
+        Some(_) => panic!("Invalid {}: {} is too small, it must be more than {} bytes", name, size, min),
+        None => panic!("Invalid {}: {}", name, size),
+    }
+}
+
+fn env_size(var: &str, default: usize, min: usize) -> usize {
+    match env::var(var) {
+        Ok(size) => parse_size(var, &size, min),
+        Err(_) => default,
+    }
+}
+
+// Usage: [--heap-size <size>] [--stack-size <size>] [input]
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let input = if args.len() == 2 { &args[1] } else { "false" };
+    let mut heap_size = env_size("SNEK_HEAP_SIZE", HEAP_SIZE, 0);
+    // The stack must have room for the compiled code above the margin
+    let mut stack_size = env_size("SNEK_STACK_SIZE", STACK_SIZE, STACK_MARGIN);
+    let mut input = "false".to_string();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let (size, min) = match arg.as_str() {
+            "--heap-size" => (&mut heap_size, 0),
+            "--stack-size" => (&mut stack_size, STACK_MARGIN),
+            _ => {
+                input = arg;
+                continue;
+            }
+        };
+        match args.next() {
+            Some(s) => *size = parse_size(&arg, &s, min),
+            None => panic!("{} needs a size", arg),
+        }
+    }
     let input = parse_input(&input);
 
-    let mut memory = Vec::<u64>::with_capacity(1000000);
+    let heap_words = heap_size / 8;
+    let mut memory = Vec::<u64>::with_capacity(heap_words);
     let buffer: *mut u64 = memory.as_mut_ptr();
+    unsafe { HEAP_START = buffer };
+    let heap = (buffer as usize, buffer as usize + heap_words * 8);
 
-    let i: u64 = unsafe { our_code_starts_here(input, buffer) };
+    // The program runs on a stack of its own, with a guard page below it
+    let program = thread::Builder::new().stack_size(stack_size).spawn(move || unsafe {
+        // The stack starts a little above this local
+        let marker = 0u8;
+        let top = ptr::addr_of!(marker) as usize;
+        let limit = top.saturating_sub(stack_size) + STACK_MARGIN;
+        our_code_starts_here(input, heap.0 as *mut u64, heap.1 as *mut u64, limit as u64)
+    });
+    let i: u64 = program.expect("could not start the program").join().unwrap();
     unsafe { snek_print(i as i64) };
 }
@@ -11,6 +11,10 @@ pub enum Reg {
     Rbp,
     Rsi,
     Rdi,
+    R8,
+    R12,
+    R13,
+    R14,
     R15,
 }
 
@@ -41,6 +45,7 @@ pub enum Instr {
 
     // Moves 
     Mov(Arg, Arg), // dst <- src
+    Lea(Reg, String), // dst <- address of label
 
     // Arithmetic operations
 
@@ -67,6 +72,11 @@ pub enum Instr {
     Je(String), // jump if ==
     Jne(String), // jump if !=
     Jo(String), // jump if overflow
+    Jl(String), // jump if <
+    Jle(String), // jump if <=
+    Jg(String), // jump if >
+    Jge(String), // jump if >=
+    Jb(String), // jump if < as unsigned
 
     // Conditional moves
     Cmove(Arg, Arg), // dst <- src if ==
@@ -77,7 +87,30 @@ pub enum Instr {
     
     // Function call related
     Call(String), // push return address, jump to label
+    CallReg(Reg), // push return address, jump to the address in reg
+    JmpReg(Reg), // jump to the address in reg
     Ret, // pop return address, jump to it
+    Push(Reg), // rsp -= 8, [rsp] <- reg
+    Pop(Reg), // reg <- [rsp], rsp += 8
+
+    // Debugging
+    Line(usize, String), // the source line and file of the code that follows, no code itself
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+// Items in a read-only data section
+pub enum Data {
+    // a_label:
+    Label(String),
+
+    // dq n
+    Quad(i64),
+
+    // dq label - base, for two labels of the code
+    Offset(String, String),
+
+    // db b_1, ..., b_n
+    Bytes(Vec<u8>),
 }
 
 // impls
@@ -104,6 +137,10 @@ fn reg_to_string(r: &Reg) -> String {
         Reg::Rbp => "rbp".to_string(),
         Reg::Rsi => "rsi".to_string(),
         Reg::Rdi => "rdi".to_string(),
+        Reg::R8 => "r8".to_string(),
+        Reg::R12 => "r12".to_string(),
+        Reg::R13 => "r13".to_string(),
+        Reg::R14 => "r14".to_string(),
         Reg::R15 => "r15".to_string(),
     }
 }
@@ -135,6 +172,7 @@ fn instr_to_string(i: &Instr) -> String {
     match i {
         Instr::Label(l) => format!("{}:", l),
         Instr::Mov(v1, v2) => format!("mov {}, {}", arg_to_string(v1), arg_to_string(v2)),
+        Instr::Lea(r, l) => format!("lea {}, [rel {}]", reg_to_string(r), l),
         Instr::Add(v1, v2) => format!("add {}, {}", arg_to_string(v1), arg_to_string(v2)),
         Instr::Sub(v1, v2) => format!("sub {}, {}", arg_to_string(v1), arg_to_string(v2)),
         Instr::Imul(v1, v2) => format!("imul {}, {}", arg_to_string(v1), arg_to_string(v2)),
@@ -154,15 +192,45 @@ fn instr_to_string(i: &Instr) -> String {
         Instr::Je(v) => format!("je {}", v),
         Instr::Jmp(v) => format!("jmp {}", v),
         Instr::Jo(v) => format!("jo {}", v),
+        Instr::Jl(v) => format!("jl {}", v),
+        Instr::Jle(v) => format!("jle {}", v),
+        Instr::Jg(v) => format!("jg {}", v),
+        Instr::Jge(v) => format!("jge {}", v),
+        Instr::Jb(v) => format!("jb {}", v),
         Instr::Call(v) => format!("call {}", v),
+        Instr::CallReg(r) => format!("call {}", reg_to_string(r)),
+        Instr::JmpReg(r) => format!("jmp {}", reg_to_string(r)),
         Instr::Ret => "ret".to_string(),
+        Instr::Push(r) => format!("push {}", reg_to_string(r)),
+        Instr::Pop(r) => format!("pop {}", reg_to_string(r)),
+        Instr::Line(line, file) => format!("%line {}+0 {}", line, file),
     }
 }
 
 pub fn instrs_to_string(instrs: Vec<Instr>) -> String {
     instrs
         .iter()
-        .map(|i| instr_to_string(i))
+        .map(instr_to_string)
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn data_item_to_string(d: &Data) -> String {
+    match d {
+        Data::Label(l) => format!("{}:", l),
+        Data::Quad(n) => format!("dq {}", n),
+        Data::Offset(label, base) => format!("dq {} - {}", label, base),
+        Data::Bytes(bytes) => format!(
+            "db {}",
+            bytes.iter().map(|b| b.to_string()).collect::<Vec<String>>().join(", ")
+        ),
+    }
+}
+
+pub fn data_to_string(data: Vec<Data>) -> String {
+    data
+        .iter()
+        .map(data_item_to_string)
         .collect::<Vec<String>>()
         .join("\n")
 }
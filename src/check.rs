@@ -0,0 +1,149 @@
+use im::{HashMap, HashSet};
+
+use crate::diagnostic::*;
+use crate::syntax::*;
+
+fn check_bound(id: &str, span: &Span, env: &HashSet<String>, diags: &mut Vec<Diagnostic>) {
+    if !env.contains(id) {
+        diags.push(Diagnostic::new(
+            ErrorKind::UnboundIdentifier,
+            format!("Unbound variable identifier {}", id),
+            span.clone(),
+        ));
+    }
+}
+
+// Checks that an expression only refers to things that exist where it is used:
+// variables in env, functions in funs as values or with the right number of
+// arguments, input only outside of functions (fun_name is None), and break only inside a loop.
+// Reports every problem to diags instead of stopping at the first
+fn check_expr(
+    e: &Expr,
+    env: &HashSet<String>,
+    funs: &HashMap<String, usize>,
+    fun_name: Option<&str>,
+    in_loop: bool,
+    diags: &mut Vec<Diagnostic>,
+) {
+    match &e.kind {
+        ExprKind::Number(_) | ExprKind::Boolean(_) => {}
+        ExprKind::Var(id) if id == "input" => {
+            if let Some(name) = fun_name {
+                diags.push(Diagnostic::new(
+                    ErrorKind::InputInFunction,
+                    format!("Invalid use of input inside function {}", name),
+                    e.span.clone(),
+                ));
+            }
+        }
+        // A function of the program, as a value
+        ExprKind::Var(id) if !env.contains(id) && funs.contains_key(id) => {}
+        ExprKind::Var(id) => check_bound(id, &e.span, env, diags),
+        ExprKind::Set(id, value) => {
+            check_bound(id, &e.span, env, diags);
+            check_expr(value, env, funs, fun_name, in_loop, diags);
+        }
+        ExprKind::Let(bindings, body) => {
+            let mut new_env = env.clone();
+            for (id, e) in bindings {
+                check_expr(e, &new_env, funs, fun_name, in_loop, diags);
+                new_env.insert(id.clone());
+            }
+            check_expr(body, &new_env, funs, fun_name, in_loop, diags);
+        }
+        ExprKind::Loop(body) => check_expr(body, env, funs, fun_name, true, diags),
+        ExprKind::Break(value) => {
+            if !in_loop {
+                diags.push(Diagnostic::new(
+                    ErrorKind::BreakOutsideLoop,
+                    "Invalid break: break outside of loop".to_string(),
+                    e.span.clone(),
+                ));
+            }
+            check_expr(value, env, funs, fun_name, in_loop, diags);
+        }
+        ExprKind::UnOp(_, e)
+        | ExprKind::Print(e)
+        | ExprKind::TupLen(e) => check_expr(e, env, funs, fun_name, in_loop, diags),
+        ExprKind::BinOp(_, e1, e2) | ExprKind::TupGet(e1, e2) => {
+            check_expr(e1, env, funs, fun_name, in_loop, diags);
+            check_expr(e2, env, funs, fun_name, in_loop, diags);
+        }
+        ExprKind::If(e1, e2, e3) | ExprKind::TupSet(e1, e2, e3) => {
+            check_expr(e1, env, funs, fun_name, in_loop, diags);
+            check_expr(e2, env, funs, fun_name, in_loop, diags);
+            check_expr(e3, env, funs, fun_name, in_loop, diags);
+        }
+        ExprKind::Block(es) | ExprKind::Tup(es) => {
+            for e in es {
+                check_expr(e, env, funs, fun_name, in_loop, diags);
+            }
+        }
+        // A variable shadows the function of the same name, and the number of
+        // arguments of the function value it holds is only known at runtime
+        ExprKind::Call(fname, args) if env.contains(fname) => {
+            for arg in args {
+                check_expr(arg, env, funs, fun_name, in_loop, diags);
+            }
+        }
+        ExprKind::Call(fname, args) => {
+            match funs.get(fname) {
+                None => diags.push(Diagnostic::new(
+                    ErrorKind::UnknownFunction,
+                    format!("Invalid call: undefined function {}", fname),
+                    e.span.clone(),
+                )),
+                Some(arity) if *arity != args.len() => diags.push(Diagnostic::new(
+                    ErrorKind::ArityMismatch,
+                    format!("Invalid call: {} expects {} arguments, but got {}", fname, arity, args.len()),
+                    e.span.clone(),
+                )),
+                Some(_) => {}
+            }
+            for arg in args {
+                check_expr(arg, env, funs, fun_name, in_loop, diags);
+            }
+        }
+        // The body runs when the lambda is called, outside of any loop
+        ExprKind::Lambda(params, body) => {
+            let mut new_env = env.clone();
+            new_env.extend(params.iter().cloned());
+            check_expr(body, &new_env, funs, fun_name, false, diags);
+        }
+        ExprKind::Apply(f, args) => {
+            check_expr(f, env, funs, fun_name, in_loop, diags);
+            for arg in args {
+                check_expr(arg, env, funs, fun_name, in_loop, diags);
+            }
+        }
+        ExprKind::Closure(_, env_tuple) => check_expr(env_tuple, env, funs, fun_name, in_loop, diags),
+    }
+}
+
+fn check_fundef(def: &FunDef, funs: &HashMap<String, usize>, diags: &mut Vec<Diagnostic>) {
+    let env: HashSet<String> = def.params.iter().cloned().collect();
+    check_expr(&def.body, &env, funs, Some(&def.name), false, diags);
+}
+
+// Static checks that run after parsing and before compilation
+// On failure, returns every problem found, ordered by source position
+pub fn check(p: &Program) -> Result<(), Vec<Diagnostic>> {
+    let mut diags = vec![];
+    // fun_env is a map from function names to their arity
+    let fun_env: HashMap<String, usize> = p
+        .defs
+        .iter()
+        .map(|def| (def.name.clone(), def.params.len()))
+        .collect();
+    for def in &p.defs {
+        check_fundef(def, &fun_env, &mut diags);
+    }
+    check_expr(&p.main, &HashSet::new(), &fun_env, None, false, &mut diags);
+
+    if diags.is_empty() {
+        Ok(())
+    } else {
+        diags.sort_by(|d1, d2| d1.span.cmp(&d2.span));
+        Err(diags)
+    }
+}
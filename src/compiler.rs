@@ -2,20 +2,22 @@ use im::HashMap;
 
 use crate::syntax::*;
 use crate::asm::*;
+use crate::errors::{operation, SnekError};
+use crate::lift::{arity, lift_lambdas};
+use crate::printer::{binop_name, unop_name};
 
 // Returns the internal representation of constant values
-fn repr(e: &Expr) -> i64 {
+fn repr(e: &ExprKind) -> i64 {
     match e {
-        Expr::Number(n) => n << 1,
-        Expr::Boolean(true) => 7,
-        Expr::Boolean(false) => 3,
+        ExprKind::Number(n) => n << 1,
+        ExprKind::Boolean(true) => 7,
+        ExprKind::Boolean(false) => 3,
         _ => panic!("Not a constant value: {:?}", e),
     }
 }
 
-fn repr_true() -> i64 { repr(&Expr::Boolean(true)) }
-fn repr_false() -> i64 { repr(&Expr::Boolean(false)) }
-fn repr_n(n: i64) -> i64 { repr(&Expr::Number(n)) }
+fn repr_true() -> i64 { repr(&ExprKind::Boolean(true)) }
+fn repr_false() -> i64 { repr(&ExprKind::Boolean(false)) }
 
 fn new_label(l: &mut i64, s: &str) -> String {
     let current = *l;
@@ -23,82 +25,260 @@ fn new_label(l: &mut i64, s: &str) -> String {
     format!("{s}_{current}")
 }
 
-// A set of instructions that copy the error code in RBX to RDI and call snek_error
+// What the compilation of a program collects and shares across functions
+#[derive(Default)]
+struct Context {
+    // The source positions of the expressions that can fail at runtime or
+    // that call a function, indexed by site id
+    spans: Vec<Span>,
+    // The label right after each call, with the site id of the call
+    returns: Vec<(String, i64)>,
+    // The number of argument slots every call reserves: the largest arity,
+    // so that any function can tail call any other in place
+    arg_slots: i64,
+    // The number of arguments each function takes as a function value
+    arities: HashMap<String, i64>,
+}
+
+// Records the source position of an expression that can fail at runtime
+// Returns the site id, which indexes the site table in the generated code
+fn new_site(ctx: &mut Context, span: &Span) -> i64 {
+    ctx.spans.push(span.clone());
+    ctx.spans.len() as i64 - 1
+}
+
+// A function in the function table: its name, the labels its code starts
+// and ends at, and the site id of its first site. The sites of a function
+// come after those of the functions before it
+struct Function {
+    name: String,
+    start: String,
+    end: String,
+    first_site: i64,
+}
+
+// A set of instructions that copy the error code in RBX to RDI,
+// the site id in RDX to RSI, and call snek_error
+// The values in RAX and RCX are passed along, for the errors that report them,
+// and so is the frame in RBP, for backtraces
 fn error_handler() -> Vec<Instr> {
     vec![
         Instr::Label("snek_error_handler".to_string()),
         Instr::Mov(Arg::Reg(Reg::Rdi), Arg::Reg(Reg::Rbx)),
-        // TODO: is this call or jump?
+        Instr::Mov(Arg::Reg(Reg::Rsi), Arg::Reg(Reg::Rdx)),
+        Instr::Mov(Arg::Reg(Reg::Rdx), Arg::Reg(Reg::Rax)),
+        Instr::Mov(Arg::Reg(Reg::R8), Arg::Reg(Reg::Rbp)),
+        // We can get here from any stack depth, so align rsp for the call
+        Instr::And(Arg::Reg(Reg::Rsp), Arg::Imm(-16)),
+        // snek_error exits, so it never returns here
         Instr::Call("snek_error".to_string()),
     ]
 }
 
-// Instructions that error with code 1 if the values in RAX and RCX are of different types
-// Not using RBX since we are using that to store the error code for the error handler
-fn error_rax_rcx_diff_type() -> Vec<Instr> {
+// Instructions that error with InvalidEqual if the values in RAX and RCX are of different types
+// The error is reported with both values, so the test is done in RBX
+fn error_rax_rcx_diff_type(site: i64) -> Vec<Instr> {
     vec![
-        // Set error code to 1
-        Instr::Mov(Arg::Reg(Reg::Rbx), Arg::Imm(1)),
         // Get the matching bits of RAX and RCX
-        Instr::Xor(Arg::Reg(Reg::Rcx), Arg::Reg(Reg::Rax)),
+        Instr::Mov(Arg::Reg(Reg::Rbx), Arg::Reg(Reg::Rcx)),
+        Instr::Xor(Arg::Reg(Reg::Rbx), Arg::Reg(Reg::Rax)),
         // and test if 1 bit is set (1st bits are equal)
-        Instr::Test(Arg::Reg(Reg::Rcx), Arg::Imm(1)),
-        // If not, jump to error handler
+        Instr::Test(Arg::Reg(Reg::Rbx), Arg::Imm(1)),
+        // If not, jump to error handler. mov leaves the flags alone
+        Instr::Mov(Arg::Reg(Reg::Rbx), Arg::Imm(SnekError::InvalidEqual.code())),
+        Instr::Mov(Arg::Reg(Reg::Rdx), Arg::Imm(site)),
         Instr::Jne("snek_error_handler".to_string()),
     ]
 }
 
-// Instructions that error with code 2 if the value in RAX is not a number
-fn error_rax_not_num() -> Vec<Instr> {
+// Instructions that error with InvalidArith if the value in RAX is not a number
+// The error is reported with the value and the operation that needed a number
+fn error_rax_not_num(site: i64, op: &str) -> Vec<Instr> {
     vec![
-        Instr::Mov(Arg::Reg(Reg::Rbx), Arg::Imm(2)),
+        Instr::Mov(Arg::Reg(Reg::Rbx), Arg::Imm(SnekError::InvalidArith.code())),
+        Instr::Mov(Arg::Reg(Reg::Rdx), Arg::Imm(site)),
+        Instr::Mov(Arg::Reg(Reg::Rcx), Arg::Imm(operation(op))),
         Instr::Test(Arg::Reg(Reg::Rax), Arg::Imm(1)),
         Instr::Jne("snek_error_handler".to_string()),
     ]
 }
 
-// Instructions that error with code 3 if there is an overflow
-fn error_overflow() -> Vec<Instr> {
+// Instructions that error with Overflow if there is an overflow
+fn error_overflow(site: i64) -> Vec<Instr> {
     vec![
-        Instr::Mov(Arg::Reg(Reg::Rbx), Arg::Imm(3)),
+        Instr::Mov(Arg::Reg(Reg::Rbx), Arg::Imm(SnekError::Overflow.code())),
+        Instr::Mov(Arg::Reg(Reg::Rdx), Arg::Imm(site)),
         Instr::Jo("snek_error_handler".to_string()),
     ]
 }
-// Instructions that error with code 4 if the value in RAX is not a tuple
-fn error_rax_not_tuple() -> Vec<Instr> {
+// Instructions that error with NotATuple if the value in RAX is not a tuple
+// The error is reported with the value and the operation that needed a tuple
+fn error_rax_not_tuple(site: i64, op: &str) -> Vec<Instr> {
     vec![
         // Copy the value to rcx
         Instr::Mov(Arg::Reg(Reg::Rcx), Arg::Reg(Reg::Rax)),
-        // get the two least significant bits
-        Instr::And(Arg::Reg(Reg::Rcx), Arg::Imm(3)),
+        // get the three least significant bits
+        Instr::And(Arg::Reg(Reg::Rcx), Arg::Imm(7)),
         // and test if they are 1 (the value is a tuple)
         Instr::Cmp(Arg::Reg(Reg::Rcx), Arg::Imm(1)),
-        // If not, jump to error handler with code 4
-        Instr::Mov(Arg::Reg(Reg::Rbx), Arg::Imm(4)),
+        // If not, jump to error handler
+        Instr::Mov(Arg::Reg(Reg::Rbx), Arg::Imm(SnekError::NotATuple.code())),
+        Instr::Mov(Arg::Reg(Reg::Rdx), Arg::Imm(site)),
+        Instr::Mov(Arg::Reg(Reg::Rcx), Arg::Imm(operation(op))),
         Instr::Jne("snek_error_handler".to_string()),
     ]
 }
 
-// Integer values are shifted left by 1
+// Instructions that error with NotAFunction if the value in RAX is not a function,
+// or with ArityMismatch if it does not take n_args arguments
+// Function values are tagged with 5 in their three least significant bits,
+// and hold their code address, arity and captured values after their header
+fn error_rax_not_function(site: i64, n_args: i64) -> Vec<Instr> {
+    vec![
+        Instr::Mov(Arg::Reg(Reg::Rcx), Arg::Reg(Reg::Rax)),
+        Instr::And(Arg::Reg(Reg::Rcx), Arg::Imm(7)),
+        Instr::Cmp(Arg::Reg(Reg::Rcx), Arg::Imm(5)),
+        Instr::Mov(Arg::Reg(Reg::Rbx), Arg::Imm(SnekError::NotAFunction.code())),
+        Instr::Mov(Arg::Reg(Reg::Rdx), Arg::Imm(site)),
+        Instr::Jne("snek_error_handler".to_string()),
+        // The arity is shifted like a number, and reported with the number of
+        // arguments, which mov loads without changing the flags
+        Instr::Mov(Arg::Reg(Reg::Rbx), Arg::Imm(SnekError::ArityMismatch.code())),
+        Instr::Mov(Arg::Reg(Reg::Rcx), Arg::Mem(maddr_bd(Reg::Rax, 16 - 5))),
+        Instr::Cmp(Arg::Reg(Reg::Rcx), Arg::Imm(n_args << 1)),
+        Instr::Mov(Arg::Reg(Reg::Rcx), Arg::Imm(n_args)),
+        Instr::Jne("snek_error_handler".to_string()),
+    ]
+}
+
+// Instructions that error with IndexOutOfBounds if the index in the stack slot at
+// stack_offset is out of the bounds of the tuple in RAX
+// The error is reported with the tuple and the index
+fn error_index_out_of_bounds(site: i64, stack_offset: i64) -> Vec<Instr> {
+    vec![
+        Instr::Mov(Arg::Reg(Reg::Rbx), Arg::Imm(SnekError::IndexOutOfBounds.code())),
+        Instr::Mov(Arg::Reg(Reg::Rdx), Arg::Imm(site)),
+        Instr::Mov(Arg::Reg(Reg::Rcx), Arg::Mem(maddr_bd(Reg::Rsp, -stack_offset))),
+        Instr::Cmp(Arg::Reg(Reg::Rcx), Arg::Imm(0)),
+        Instr::Jl("snek_error_handler".to_string()),
+        // Both the index and the size are shifted left by 1, so they compare
+        // the same as they would unshifted
+        Instr::Cmp(Arg::Reg(Reg::Rcx), Arg::Mem(maddr_bd(Reg::Rax, -1))),
+        Instr::Jge("snek_error_handler".to_string()),
+    ]
+}
+
+// Instructions that error with StackOverflow if rsp is below the stack limit in r12
+// The limit leaves room for the frame of the function and the runtime
+// functions it calls
+fn error_stack_overflow(site: i64) -> Vec<Instr> {
+    vec![
+        Instr::Mov(Arg::Reg(Reg::Rbx), Arg::Imm(SnekError::StackOverflow.code())),
+        Instr::Mov(Arg::Reg(Reg::Rdx), Arg::Imm(site)),
+        // Addresses compare as unsigned
+        Instr::Cmp(Arg::Reg(Reg::Rsp), Arg::Reg(Reg::R12)),
+        Instr::Jb("snek_error_handler".to_string()),
+    ]
+}
+
+// Instructions that make room for words on the heap, calling the garbage
+// collector when the heap is full, and error with OutOfMemory if it is still full
+// after that. r14 holds the end of the heap
+// The collector finds its roots in the stack, so every live value must be
+// in a stack slot below si
+fn reserve_heap(words: i64, si: i64, l: &mut i64, site: i64) -> Vec<Instr> {
+    let room_label = new_label(l, "heap_room");
+    // Aligned for the call like in Print
+    let index = if si % 2 == 1 { si } else { si + 1 };
+    let stack_offset = index * 8;
+    vec![
+        Instr::Mov(Arg::Reg(Reg::Rax), Arg::Reg(Reg::R15)),
+        Instr::Add(Arg::Reg(Reg::Rax), Arg::Imm(words * 8)),
+        Instr::Cmp(Arg::Reg(Reg::Rax), Arg::Reg(Reg::R14)),
+        Instr::Jle(room_label.clone()),
+        Instr::Mov(Arg::Mem(maddr_bd(Reg::Rsp, -stack_offset)), Arg::Reg(Reg::Rdi)),
+        // snek_gc(top of the stack, bottom of the stack, heap pointer)
+        // returns the new heap pointer
+        Instr::Sub(Arg::Reg(Reg::Rsp), Arg::Imm(stack_offset)),
+        Instr::Mov(Arg::Reg(Reg::Rdi), Arg::Reg(Reg::Rsp)),
+        Instr::Mov(Arg::Reg(Reg::Rsi), Arg::Reg(Reg::R13)),
+        Instr::Mov(Arg::Reg(Reg::Rdx), Arg::Reg(Reg::R15)),
+        Instr::Call("snek_gc".to_string()),
+        Instr::Add(Arg::Reg(Reg::Rsp), Arg::Imm(stack_offset)),
+        Instr::Mov(Arg::Reg(Reg::Rdi), Arg::Mem(maddr_bd(Reg::Rsp, -stack_offset))),
+        Instr::Mov(Arg::Reg(Reg::R15), Arg::Reg(Reg::Rax)),
+        Instr::Add(Arg::Reg(Reg::Rax), Arg::Imm(words * 8)),
+        Instr::Mov(Arg::Reg(Reg::Rbx), Arg::Imm(SnekError::OutOfMemory.code())),
+        Instr::Mov(Arg::Reg(Reg::Rdx), Arg::Imm(site)),
+        Instr::Cmp(Arg::Reg(Reg::Rax), Arg::Reg(Reg::R14)),
+        Instr::Jg("snek_error_handler".to_string()),
+        Instr::Label(room_label),
+    ]
+}
+
+fn line(span: &Span) -> Instr {
+    Instr::Line(span.line, span.file.to_string())
+}
+
+// Instructions that replace the arguments of the current function with the
+// n values in the stack slots from si on, and leave its frame like the
+// epilogue of compile_fundef, without returning. Our caller reserved
+// ctx.arg_slots slots, so n can be up to that
+fn leave_for_tail_call(si: i64, n: i64) -> Vec<Instr> {
+    let mut instrs = vec![];
+    for i in 0..n {
+        instrs.push(Instr::Mov(
+            Arg::Reg(Reg::Rax),
+            Arg::Mem(maddr_bd(Reg::Rsp, -(si + i) * 8)),
+        ));
+        instrs.push(Instr::Mov(
+            Arg::Mem(maddr_bd(Reg::Rbp, 16 + i * 8)),
+            Arg::Reg(Reg::Rax),
+        ));
+    }
+    instrs.push(Instr::Mov(Arg::Reg(Reg::Rsp), Arg::Reg(Reg::Rbp)));
+    instrs.push(Instr::Pop(Reg::Rbp));
+    instrs
+}
+
+// Marks the code of each expression with its source line, for debuggers
 fn compile_expr(
     e: &Expr,
     si: i64,
     env: &HashMap<String, i64>,
     brake: &String,
+    tail: bool,
     l: &mut i64,
+    ctx: &mut Context,
 ) -> Vec<Instr> {
-    match e {
-        Expr::Number(n) => {
+    let mut instrs = vec![line(&e.span)];
+    instrs.append(&mut compile_expr_kind(e, si, env, brake, tail, l, ctx));
+    instrs
+}
+
+// Integer values are shifted left by 1
+fn compile_expr_kind(
+    e: &Expr,
+    si: i64,
+    env: &HashMap<String, i64>,
+    brake: &String,
+    tail: bool,
+    l: &mut i64,
+    ctx: &mut Context,
+) -> Vec<Instr> {
+    let span = &e.span;
+    match &e.kind {
+        ExprKind::Number(n) => {
             if let (our_n, false) = n.overflowing_mul(2) {
                 vec![Instr::Mov(Arg::Reg(Reg::Rax), Arg::Imm(our_n))]
             } else {
                 panic!("Invalid integer constant: overflow {}", n)
             }
         }
-        Expr::Boolean(true) => vec![Instr::Mov(Arg::Reg(Reg::Rax), Arg::Imm(repr_true()))],
-        Expr::Boolean(false) => vec![Instr::Mov(Arg::Reg(Reg::Rax), Arg::Imm(repr_false()))],
-        Expr::Var(id) if id == "input" => vec![Instr::Mov(Arg::Reg(Reg::Rax), Arg::Reg(Reg::Rdi))],
-        Expr::Var(id) => {
+        ExprKind::Boolean(true) => vec![Instr::Mov(Arg::Reg(Reg::Rax), Arg::Imm(repr_true()))],
+        ExprKind::Boolean(false) => vec![Instr::Mov(Arg::Reg(Reg::Rax), Arg::Imm(repr_false()))],
+        ExprKind::Var(id) if id == "input" => vec![Instr::Mov(Arg::Reg(Reg::Rax), Arg::Reg(Reg::Rdi))],
+        ExprKind::Var(id) => {
             if env.contains_key(id) {
                 vec![Instr::Mov(
                     Arg::Reg(Reg::Rax),
@@ -108,12 +288,13 @@ fn compile_expr(
                 panic!("Unbound variable identifier {}", id)
             }
         }
-        Expr::Let(bindings, body) => {
+        ExprKind::Let(bindings, body) => {
             let mut instrs = vec![];
             let mut new_env = env.clone();
-            for (i, (id, e)) in bindings.into_iter().enumerate() {
+            for (i, (id, e)) in bindings.iter().enumerate() {
+                // Binding i lives at si + i, so its value can only use the slots above it
                 let stack_offset = (si + i as i64) * 8;
-                instrs.append(&mut compile_expr(e, si + 1, &new_env, brake, l));
+                instrs.append(&mut compile_expr(e, si + i as i64, &new_env, brake, false, l, ctx));
                 instrs.push(Instr::Mov(
                     Arg::Mem(maddr_bd(Reg::Rsp, -stack_offset)),
                     Arg::Reg(Reg::Rax),
@@ -125,22 +306,25 @@ fn compile_expr(
                 si + bindings.len() as i64,
                 &new_env,
                 brake,
+                tail,
                 l,
+                ctx,
             ));
             instrs
         }
-        Expr::UnOp(op, e) => {
-            let mut instrs = compile_expr(e, si, env, brake, l);
+        ExprKind::UnOp(op, e) => {
+            let site = new_site(ctx, span);
+            let mut instrs = compile_expr(e, si, env, brake, false, l, ctx);
             match op {
                 Op1::Add1 => {
-                    instrs.append(&mut error_rax_not_num());
+                    instrs.append(&mut error_rax_not_num(site, unop_name(op)));
                     instrs.push(Instr::Add(Arg::Reg(Reg::Rax), Arg::Imm(2)));
-                    instrs.append(&mut error_overflow());
+                    instrs.append(&mut error_overflow(site));
                 }
                 Op1::Sub1 => {
-                    instrs.append(&mut error_rax_not_num());
+                    instrs.append(&mut error_rax_not_num(site, unop_name(op)));
                     instrs.push(Instr::Sub(Arg::Reg(Reg::Rax), Arg::Imm(2)));
-                    instrs.append(&mut error_overflow());
+                    instrs.append(&mut error_overflow(site));
                 }
                 Op1::IsNum => {
                     let mut comp_instrs = vec![
@@ -154,22 +338,24 @@ fn compile_expr(
                 }
                 Op1::IsBool => {
                     let mut comp_instrs = vec![
-                        Instr::Test(Arg::Reg(Reg::Rax), Arg::Imm(1)),
-                        Instr::Mov(Arg::Reg(Reg::Rax), Arg::Imm(repr_true())),
-                        Instr::Mov(Arg::Reg(Reg::Rbx), Arg::Imm(repr_false())),
+                        // Booleans end in 0b11, tuples in 0b01 and numbers in 0b0
+                        Instr::And(Arg::Reg(Reg::Rax), Arg::Imm(3)),
+                        Instr::Cmp(Arg::Reg(Reg::Rax), Arg::Imm(3)),
+                        Instr::Mov(Arg::Reg(Reg::Rax), Arg::Imm(repr_false())),
+                        Instr::Mov(Arg::Reg(Reg::Rbx), Arg::Imm(repr_true())),
                         Instr::Cmove(Arg::Reg(Reg::Rax), Arg::Reg(Reg::Rbx)),
                     ];
                     instrs.append(&mut comp_instrs);
                 }
-                _ => panic!("Unimplemented unary operator: {:?}", op),
             }
             instrs
         }
-        Expr::BinOp(op, e1, e2) => {
+        ExprKind::BinOp(op, e1, e2) => {
+            let site = new_site(ctx, span);
             match op {
                 Op2::Equal => {
                     // First evaluate e1
-                    let mut instrs = compile_expr(e1, si, env, brake, l);
+                    let mut instrs = compile_expr(e1, si, env, brake, false, l, ctx);
                     // Save the result in the current stack index
                     let stack_offset = si * 8;
                     instrs.push(Instr::Mov(
@@ -177,14 +363,14 @@ fn compile_expr(
                         Arg::Reg(Reg::Rax),
                     ));
                     // Then evaluate e2
-                    instrs.append(&mut compile_expr(e2, si + 1, env, brake, l));
+                    instrs.append(&mut compile_expr(e2, si + 1, env, brake, false, l, ctx));
                     // Copy the result of e1 to rcx
                     instrs.push(Instr::Mov(
                         Arg::Reg(Reg::Rcx),
                         Arg::Mem(maddr_bd(Reg::Rsp, -stack_offset)),
                     ));
                     // Error if e1 and e2 are of different types
-                    instrs.append(&mut error_rax_rcx_diff_type());
+                    instrs.append(&mut error_rax_rcx_diff_type(site));
                     // If they are of the same type, do a regular comparison
                     // and set the result accordingly
                     instrs.append(&mut vec![
@@ -204,9 +390,9 @@ fn compile_expr(
                 | Op2::Greater
                 | Op2::GreaterEqual => {
                     // Evaluate e1
-                    let mut instrs = compile_expr(e1, si, env, brake, l);
+                    let mut instrs = compile_expr(e1, si, env, brake, false, l, ctx);
                     // error if the result is not a number
-                    instrs.append(&mut error_rax_not_num());
+                    instrs.append(&mut error_rax_not_num(site, binop_name(op)));
                     // Otherwise, save result in the current stack index
                     let stack_offset = si * 8;
                     instrs.push(Instr::Mov(
@@ -214,9 +400,9 @@ fn compile_expr(
                         Arg::Reg(Reg::Rax),
                     ));
                     // Evaluate e2
-                    instrs.append(&mut compile_expr(e2, si + 1, env, brake, l));
+                    instrs.append(&mut compile_expr(e2, si + 1, env, brake, false, l, ctx));
                     // error if the result is not a number
-                    instrs.append(&mut error_rax_not_num());
+                    instrs.append(&mut error_rax_not_num(site, binop_name(op)));
                     // the remaining instructions depend on the operator
                     match op {
                         Op2::Plus => {
@@ -224,7 +410,7 @@ fn compile_expr(
                                 Arg::Reg(Reg::Rax),
                                 Arg::Mem(maddr_bd(Reg::Rsp, -stack_offset)),
                             ));
-                            instrs.append(&mut error_overflow());
+                            instrs.append(&mut error_overflow(site));
                         }
                         Op2::Minus => {
                             // The expected order is the opposite than the sub instruction
@@ -232,7 +418,7 @@ fn compile_expr(
                                 Arg::Mem(maddr_bd(Reg::Rsp, -stack_offset)),
                                 Arg::Reg(Reg::Rax),
                             ));
-                            instrs.append(&mut error_overflow());
+                            instrs.append(&mut error_overflow(site));
                             instrs.push(Instr::Mov(
                                 Arg::Reg(Reg::Rax),
                                 Arg::Mem(maddr_bd(Reg::Rsp, -stack_offset)),
@@ -247,7 +433,7 @@ fn compile_expr(
                                     Arg::Mem(maddr_bd(Reg::Rsp, -stack_offset)),
                                 ),
                             ]);
-                            instrs.append(&mut error_overflow());
+                            instrs.append(&mut error_overflow(site));
                         }
                         // for the comparison operators
                         _ => {
@@ -278,45 +464,45 @@ fn compile_expr(
                 }
             }
         }
-        Expr::If(cond, thn, els) => {
+        ExprKind::If(cond, thn, els) => {
             let else_label = new_label(l, "ifelse");
             let end_label = new_label(l, "ifend");
-            let mut instrs = compile_expr(cond, si, env, brake, l);
+            let mut instrs = compile_expr(cond, si, env, brake, false, l, ctx);
             // TODO: We might want to check that the result is a boolean
             // If result of cond is false, jump to else
             instrs.push(Instr::Cmp(Arg::Reg(Reg::Rax), Arg::Imm(repr_false())));
             instrs.push(Instr::Je(else_label.clone()));
             // We execute thn instructions and jump to end
-            instrs.append(&mut compile_expr(thn, si, env, brake, l));
+            instrs.append(&mut compile_expr(thn, si, env, brake, tail, l, ctx));
             instrs.push(Instr::Jmp(end_label.clone()));
             // We define the else label, no need to jump after
             instrs.push(Instr::Label(else_label.clone()));
-            instrs.append(&mut compile_expr(els, si, env, brake, l));
+            instrs.append(&mut compile_expr(els, si, env, brake, tail, l, ctx));
             instrs.push(Instr::Label(end_label.clone()));
             instrs
         }
-        Expr::Loop(body) => {
+        ExprKind::Loop(body) => {
             let start_label = new_label(l, "loop");
             let end_label = new_label(l, "loopend");
             let mut instrs = vec![Instr::Label(start_label.clone())];
-            instrs.append(&mut compile_expr(body, si, env, &end_label, l));
+            instrs.append(&mut compile_expr(body, si, env, &end_label, false, l, ctx));
             instrs.push(Instr::Jmp(start_label.clone()));
             instrs.push(Instr::Label(end_label.clone()));
             instrs
         }
-        Expr::Break(e) => {
+        ExprKind::Break(e) => {
             if !brake.is_empty() {
-                let mut instrs = compile_expr(e, si, env, brake, l);
+                let mut instrs = compile_expr(e, si, env, brake, false, l, ctx);
                 instrs.push(Instr::Jmp(brake.clone()));
                 instrs
             } else {
                 panic!("break outside of loop: {:?}", e);
             }
         }
-        Expr::Set(id, e) => {
+        ExprKind::Set(id, e) => {
             if env.contains_key(id) {
                 let id_offset = env.get(id).unwrap();
-                let mut instrs = compile_expr(e, si, env, brake, l);
+                let mut instrs = compile_expr(e, si, env, brake, false, l, ctx);
                 instrs.push(Instr::Mov(
                     Arg::Mem(maddr_bd(Reg::Rsp, -*id_offset)),
                     Arg::Reg(Reg::Rax),
@@ -326,16 +512,18 @@ fn compile_expr(
                 panic!("Unbound variable identifier {}", id);
             }
         }
-        Expr::Block(es) => {
-            es.into_iter()
-            .map(|e| compile_expr(e, si, env, brake, l))
-            .flatten()
+        ExprKind::Block(es) => {
+            // Only the last expression is in the tail position of the block
+            es.iter()
+            .enumerate()
+            .flat_map(|(i, e)| compile_expr(e, si, env, brake, tail && i == es.len() - 1, l, ctx))
             .collect()
         }
-        Expr::Print(e) => {
-            let mut instrs = compile_expr(e, si, env, brake, l);
-            // We need to use stack offset that is 16 bit aligned
-            let index = if si % 2 == 0 { si } else { si + 1 };
+        ExprKind::Print(e) => {
+            let mut instrs = compile_expr(e, si, env, brake, false, l, ctx);
+            // rsp is 8 bytes off a 16 byte boundary on entry, so moving it
+            // by an odd number of words aligns it for the call
+            let index = if si % 2 == 1 { si } else { si + 1 };
             let stack_offset = index * 8;
             instrs.append(&mut vec![
                 // Save rdi before the call
@@ -353,13 +541,14 @@ fn compile_expr(
             ]);
             instrs
         }
-        Expr::Tup(es) => {
+        ExprKind::Tup(es) => {
+            let site = new_site(ctx, span);
             let size = es.len();
             let mut instrs = vec![];
-            for (i, expr) in es.into_iter().enumerate() {
+            for (i, expr) in es.iter().enumerate() {
                 let current_si = si + i as i64;
                 // Compile each member of the tuple
-                instrs.append(&mut compile_expr(expr, current_si, env, brake, l));
+                instrs.append(&mut compile_expr(expr, current_si, env, brake, false, l, ctx));
                 // Save the result in the current stack index
                 instrs.push(Instr::Mov(
                     // Arg::RegOffset(Reg::Rsp, -current_si * 8),
@@ -367,6 +556,7 @@ fn compile_expr(
                     Arg::Reg(Reg::Rax),
                 ));
             }
+            instrs.append(&mut reserve_heap(size as i64 + 1, si + size as i64, l, site));
             // Set rbs to the size of the tuple
             instrs.push(Instr::Mov(Arg::Reg(Reg::Rbx), Arg::Imm((size << 1) as i64)));
             // Set r15 (next new heap location) to the size of the tuple
@@ -397,23 +587,25 @@ fn compile_expr(
             instrs.push(Instr::Add(Arg::Reg(Reg::R15), Arg::Imm((size + 1) as i64 * 8)));
             instrs
         }
-        Expr::TupGet(e, idx) => {
+        ExprKind::TupGet(e, idx) => {
+            let site = new_site(ctx, span);
             // first evaluate the index
-            let mut instrs = compile_expr(idx, si, env, brake, l);
+            let mut instrs = compile_expr(idx, si, env, brake, false, l, ctx);
             // error if idx is not a number
-            instrs.append(&mut error_rax_not_num());
+            instrs.append(&mut error_rax_not_num(site, "tup-get"));
             // save the result in the current stack index
             instrs.push(Instr::Mov(
                 Arg::Mem(maddr_bd(Reg::Rsp, -si * 8)),
                 Arg::Reg(Reg::Rax),
             ));
             // evaluate the tuple
-            instrs.append(&mut compile_expr(e, si + 1, env, brake, l));
+            instrs.append(&mut compile_expr(e, si + 1, env, brake, false, l, ctx));
             // error if the value is not a tuple
-            instrs.append(&mut error_rax_not_tuple());
+            instrs.append(&mut error_rax_not_tuple(site, "tup-get"));
+            // or if the index is out of bounds
+            instrs.append(&mut error_index_out_of_bounds(site, si * 8));
             // get the actual address by subtracting 1 from rax
             instrs.push(Instr::Sub(Arg::Reg(Reg::Rax), Arg::Imm(1)));
-            // TODO: check if the index is out of bounds
             // get the index to rbx
             instrs.push(Instr::Mov(
                 Arg::Reg(Reg::Rbx),
@@ -429,30 +621,32 @@ fn compile_expr(
             ));
             instrs
         }
-        Expr::TupSet(t, i, e) => {
+        ExprKind::TupSet(t, i, e) => {
+            let site = new_site(ctx, span);
             // first evaluate the index
-            let mut instrs = compile_expr(i, si, env, brake, l);
+            let mut instrs = compile_expr(i, si, env, brake, false, l, ctx);
             // error if idx is not a number
-            instrs.append(&mut error_rax_not_num());
+            instrs.append(&mut error_rax_not_num(site, "tup-set!"));
             // save the result in the current stack index
             instrs.push(Instr::Mov(
                 Arg::Mem(maddr_bd(Reg::Rsp, -si * 8)),
                 Arg::Reg(Reg::Rax),
             ));
             // evaluate e
-            instrs.append(&mut compile_expr(e, si + 1, env, brake, l));
+            instrs.append(&mut compile_expr(e, si + 1, env, brake, false, l, ctx));
             // save the result in the current stack index
             instrs.push(Instr::Mov(
                 Arg::Mem(maddr_bd(Reg::Rsp, -(si + 1) * 8)),
                 Arg::Reg(Reg::Rax),
             ));
             // evaluate the tuple
-            instrs.append(&mut compile_expr(t, si + 2, env, brake, l));
+            instrs.append(&mut compile_expr(t, si + 2, env, brake, false, l, ctx));
             // error if the value is not a tuple
-            instrs.append(&mut error_rax_not_tuple());
+            instrs.append(&mut error_rax_not_tuple(site, "tup-set!"));
+            // or if the index is out of bounds
+            instrs.append(&mut error_index_out_of_bounds(site, si * 8));
             // get the actual address by subtracting 1 from rax
             instrs.push(Instr::Sub(Arg::Reg(Reg::Rax), Arg::Imm(1)));
-            // TODO: check if the index is out of bounds
             // get idx to rbx
             instrs.push(Instr::Mov(
                 Arg::Reg(Reg::Rbx),
@@ -475,11 +669,12 @@ fn compile_expr(
             instrs.push(Instr::Add(Arg::Reg(Reg::Rax), Arg::Imm(1)));
             instrs
         }
-        Expr::TupLen(t) => {
+        ExprKind::TupLen(t) => {
+            let site = new_site(ctx, span);
             // evaluate the tuple
-            let mut instrs = compile_expr(t, si, env, brake, l);
+            let mut instrs = compile_expr(t, si, env, brake, false, l, ctx);
             // error if the value is not a tuple
-            instrs.append(&mut error_rax_not_tuple());
+            instrs.append(&mut error_rax_not_tuple(site, "tup-len"));
             // get the actual address by subtracting 1 from rax
             instrs.push(Instr::Sub(Arg::Reg(Reg::Rax), Arg::Imm(1)));
             // the size of the tuple is stored exactly at the address of the tuple
@@ -489,18 +684,44 @@ fn compile_expr(
             ));
             instrs
         }
-        Expr::Call(fname, args) => {
-            // TODO: Check that the function exists and has the right arity
-            let n_args = args.len();
-            // After setting up the call, rsp will move by 8 * n_args
-            let new_rsp_offset = si + n_args as i64;
+        ExprKind::Call(fname, args) if tail => {
+            // The result of the call is the result of this function, so the
+            // callee takes over the frame: the arguments overwrite ours, and
+            // it returns straight to our caller
+            // They are all computed first, since they can use our parameters
+            let mut instrs = vec![];
+            for (i, arg) in args.iter().enumerate() {
+                let current_si = si + i as i64;
+                instrs.append(&mut compile_expr(arg, current_si, env, brake, false, l, ctx));
+                instrs.push(Instr::Mov(
+                    Arg::Mem(maddr_bd(Reg::Rsp, -current_si * 8)),
+                    Arg::Reg(Reg::Rax),
+                ));
+            }
+            instrs.append(&mut leave_for_tail_call(si, args.len() as i64));
+            instrs.push(Instr::Jmp(fname.clone()));
+            instrs
+        }
+        ExprKind::Call(fname, args) => {
+            // The checker guarantees that the function exists and has the right arity
+            let site = new_site(ctx, span);
+            let return_label = new_label(l, "call_return");
+            // After setting up the call, rsp will move by 8 * ctx.arg_slots,
+            // plus one padding word if needed to keep it 16 byte aligned,
+            // like in Print. The slots past the arguments let the callee make
+            // tail calls to functions with more parameters than it has
+            let new_rsp_offset = if (si + ctx.arg_slots) % 2 == 1 {
+                si + ctx.arg_slots
+            } else {
+                si + ctx.arg_slots + 1
+            };
             // We actually can compile the arguments using this stack index + 1
             // since those will be untouched, and will make our life easier by
             // avoiding flipping the order of the arguments for the call
             let mut instrs = vec![];
             for (i, arg) in args.iter().enumerate() {
                 // compile using an index that is always safe
-                instrs.append(&mut compile_expr(arg, new_rsp_offset + 1, env, brake, l));
+                instrs.append(&mut compile_expr(arg, new_rsp_offset + 1, env, brake, false, l, ctx));
                 // start populating the args from the new rsp offset up
                 instrs.push(Instr::Mov(
                     Arg::Mem(maddr_bd(Reg::Rsp, (i as i64 - new_rsp_offset) * 8)),
@@ -516,6 +737,9 @@ fn compile_expr(
             instrs.push(Instr::Sub(Arg::Reg(Reg::Rsp), Arg::Imm(new_rsp_offset * 8)));
             // Finally, call the function
             instrs.push(Instr::Call(fname.clone()));
+            // where it returns to tells backtraces which call this was
+            instrs.push(Instr::Label(return_label.clone()));
+            ctx.returns.push((return_label, site));
             // Restore rsp
             instrs.push(Instr::Add(Arg::Reg(Reg::Rsp), Arg::Imm(new_rsp_offset * 8)));
             // and restore rdi
@@ -525,55 +749,315 @@ fn compile_expr(
             ));
             instrs
         }
-        _ => panic!("Unimplemented expression: {:?}", e),
+        ExprKind::Lambda(..) => panic!("Lambdas are lifted before compilation"),
+        ExprKind::Closure(fname, env_tuple) => {
+            let site = new_site(ctx, span);
+            let mut instrs = compile_expr(env_tuple, si, env, brake, false, l, ctx);
+            instrs.push(Instr::Mov(Arg::Mem(maddr_bd(Reg::Rsp, -si * 8)), Arg::Reg(Reg::Rax)));
+            instrs.append(&mut reserve_heap(4, si + 1, l, site));
+            // The header is the one of a tuple of the 3 words after it, so
+            // that the garbage collector can go through function values
+            instrs.append(&mut vec![
+                Instr::Mov(Arg::Reg(Reg::Rbx), Arg::Imm(3 << 1)),
+                Instr::Mov(Arg::Mem(maddr_b(Reg::R15)), Arg::Reg(Reg::Rbx)),
+                Instr::Lea(Reg::Rax, fname.clone()),
+                Instr::Mov(Arg::Mem(maddr_bd(Reg::R15, 8)), Arg::Reg(Reg::Rax)),
+                Instr::Mov(Arg::Reg(Reg::Rax), Arg::Imm(ctx.arities[fname] << 1)),
+                Instr::Mov(Arg::Mem(maddr_bd(Reg::R15, 16)), Arg::Reg(Reg::Rax)),
+                Instr::Mov(Arg::Reg(Reg::Rax), Arg::Mem(maddr_bd(Reg::Rsp, -si * 8))),
+                Instr::Mov(Arg::Mem(maddr_bd(Reg::R15, 24)), Arg::Reg(Reg::Rax)),
+                // Tag the address with 5
+                Instr::Mov(Arg::Reg(Reg::Rax), Arg::Reg(Reg::R15)),
+                Instr::Add(Arg::Reg(Reg::Rax), Arg::Imm(5)),
+                Instr::Add(Arg::Reg(Reg::R15), Arg::Imm(4 * 8)),
+            ]);
+            instrs
+        }
+        // A function value is called with its captured values after the
+        // arguments. The callee has to be checked before the arguments are
+        // computed, and kept at si while they are
+        // In tail position, our caller must have reserved room for both
+        ExprKind::Apply(f, args) if tail && (args.len() as i64) < ctx.arg_slots => {
+            let site = new_site(ctx, span);
+            let n_args = args.len() as i64;
+            let mut instrs = compile_expr(f, si, env, brake, false, l, ctx);
+            instrs.append(&mut error_rax_not_function(site, n_args));
+            instrs.push(Instr::Mov(Arg::Mem(maddr_bd(Reg::Rsp, -si * 8)), Arg::Reg(Reg::Rax)));
+            for (i, arg) in args.iter().enumerate() {
+                let current_si = si + 1 + i as i64;
+                instrs.append(&mut compile_expr(arg, current_si, env, brake, false, l, ctx));
+                instrs.push(Instr::Mov(
+                    Arg::Mem(maddr_bd(Reg::Rsp, -current_si * 8)),
+                    Arg::Reg(Reg::Rax),
+                ));
+            }
+            // The captured values go after the arguments, and the code
+            // address stays in rcx
+            instrs.append(&mut vec![
+                Instr::Mov(Arg::Reg(Reg::Rax), Arg::Mem(maddr_bd(Reg::Rsp, -si * 8))),
+                Instr::Mov(Arg::Reg(Reg::Rcx), Arg::Mem(maddr_bd(Reg::Rax, 24 - 5))),
+                Instr::Mov(Arg::Mem(maddr_bd(Reg::Rsp, -(si + 1 + n_args) * 8)), Arg::Reg(Reg::Rcx)),
+                Instr::Mov(Arg::Reg(Reg::Rcx), Arg::Mem(maddr_bd(Reg::Rax, 8 - 5))),
+            ]);
+            instrs.append(&mut leave_for_tail_call(si + 1, n_args + 1));
+            instrs.push(Instr::JmpReg(Reg::Rcx));
+            instrs
+        }
+        ExprKind::Apply(f, args) => {
+            let site = new_site(ctx, span);
+            let return_label = new_label(l, "call_return");
+            let n_args = args.len() as i64;
+            // Like in Call, with the function value at si and rdi at si + 1
+            // A call with more arguments than any function takes still
+            // reserves room for them, and fails the arity check
+            let slots = ctx.arg_slots.max(n_args + 1);
+            let new_rsp_offset = if (si + 1 + slots) % 2 == 1 {
+                si + 1 + slots
+            } else {
+                si + 2 + slots
+            };
+            let mut instrs = compile_expr(f, si, env, brake, false, l, ctx);
+            instrs.append(&mut error_rax_not_function(site, n_args));
+            instrs.push(Instr::Mov(Arg::Mem(maddr_bd(Reg::Rsp, -si * 8)), Arg::Reg(Reg::Rax)));
+            for (i, arg) in args.iter().enumerate() {
+                instrs.append(&mut compile_expr(arg, new_rsp_offset + 1, env, brake, false, l, ctx));
+                instrs.push(Instr::Mov(
+                    Arg::Mem(maddr_bd(Reg::Rsp, (i as i64 - new_rsp_offset) * 8)),
+                    Arg::Reg(Reg::Rax),
+                ));
+            }
+            // The collector may have moved the function value
+            instrs.append(&mut vec![
+                Instr::Mov(Arg::Reg(Reg::Rax), Arg::Mem(maddr_bd(Reg::Rsp, -si * 8))),
+                Instr::Mov(Arg::Reg(Reg::Rcx), Arg::Mem(maddr_bd(Reg::Rax, 24 - 5))),
+                Instr::Mov(Arg::Mem(maddr_bd(Reg::Rsp, (n_args - new_rsp_offset) * 8)), Arg::Reg(Reg::Rcx)),
+                Instr::Mov(Arg::Reg(Reg::Rax), Arg::Mem(maddr_bd(Reg::Rax, 8 - 5))),
+                Instr::Mov(Arg::Mem(maddr_bd(Reg::Rsp, -(si + 1) * 8)), Arg::Reg(Reg::Rdi)),
+                Instr::Sub(Arg::Reg(Reg::Rsp), Arg::Imm(new_rsp_offset * 8)),
+                Instr::CallReg(Reg::Rax),
+                Instr::Label(return_label.clone()),
+                Instr::Add(Arg::Reg(Reg::Rsp), Arg::Imm(new_rsp_offset * 8)),
+                Instr::Mov(Arg::Reg(Reg::Rdi), Arg::Mem(maddr_bd(Reg::Rsp, -(si + 1) * 8))),
+            ]);
+            ctx.returns.push((return_label, site));
+            instrs
+        }
     }
 }
 
-// fun_env is a map from function names to their arity
-// fn compile_fundef(def: &FunDef, labels: &mut i64, fun_env: &mut HashMap<String, i64>) -> Vec<Instr> {
-fn compile_fundef(def: &FunDef, labels: &mut i64) -> Vec<Instr> {
+// The arguments are above the return address, the saved rbp and a padding word
+// A tail call in the body replaces them with the arguments of the callee
+fn compile_fundef(
+    def: &FunDef,
+    labels: &mut i64,
+    ctx: &mut Context,
+    functions: &mut Vec<Function>,
+) -> Vec<Instr> {
     let body_env: HashMap<String, i64> = def
         .params
         .iter()
         .enumerate()
-        .map(|(i, id)| (id.clone(), - (i as i64 + 1) * 8 ))
+        .map(|(i, id)| (id.clone(), - (i as i64 + 3) * 8 ))
         .collect();
-    let mut body_instrs = compile_expr(&def.body, 2, &body_env, &String::new(), labels);
-    let mut instrs = vec![Instr::Label(def.name.clone())];
+    let first_site = ctx.spans.len() as i64;
+    let site = new_site(ctx, &def.span);
+    let mut body_instrs = compile_expr(&def.body, 2, &body_env, &String::new(), true, labels, ctx);
+    let end_label = new_label(labels, "fun_end");
+    let mut instrs = vec![Instr::Label(def.name.clone()), line(&def.span)];
+    // Keep the chain of frames that backtraces follow, with rsp 8 bytes off
+    // a 16 byte boundary like on entry
+    instrs.push(Instr::Push(Reg::Rbp));
+    instrs.push(Instr::Mov(Arg::Reg(Reg::Rbp), Arg::Reg(Reg::Rsp)));
+    instrs.push(Instr::Sub(Arg::Reg(Reg::Rsp), Arg::Imm(8)));
+    // Recursion is the only way to use much of the stack, so every call is checked
+    instrs.append(&mut error_stack_overflow(site));
     instrs.append(&mut body_instrs);
+    instrs.push(Instr::Mov(Arg::Reg(Reg::Rsp), Arg::Reg(Reg::Rbp)));
+    instrs.push(Instr::Pop(Reg::Rbp));
     instrs.push(Instr::Ret);
+    instrs.push(Instr::Label(end_label.clone()));
+    functions.push(Function { name: def.name.clone(), start: def.name.clone(), end: end_label, first_site });
     instrs
 }
 
-fn compile_program(p: &Program) -> (Vec<Instr>, Vec<Instr>) {
+fn compile_program(p: &Program, ctx: &mut Context, functions: &mut Vec<Function>) -> (Vec<Instr>, Vec<Instr>) {
+    let p = &lift_lambdas(p);
     let mut labels = 0;
     let mut defs = vec![];
+    ctx.arg_slots = p.defs.iter().map(|def| def.params.len() as i64).max().unwrap_or(0);
+    ctx.arities = p.defs.iter().map(|def| (def.name.clone(), arity(def) as i64)).collect();
     for def in &p.defs {
-        defs.append(&mut compile_fundef(def, &mut labels));
+        defs.append(&mut compile_fundef(def, &mut labels, ctx, functions));
     }
-    let main = compile_expr(&p.main, 2, &HashMap::new(), &String::new(), &mut labels);
+    let first_site = ctx.spans.len() as i64;
+    let main = compile_expr(&p.main, 2, &HashMap::new(), &String::new(), false, &mut labels, ctx);
+    functions.push(Function {
+        name: "main".to_string(),
+        start: "our_code_starts_here".to_string(),
+        end: "our_code_ends_here".to_string(),
+        first_site,
+    });
     (defs, main)
 }
 
-pub fn compile(p: &Program) -> String {
-    let prelude = instrs_to_string(error_handler());
+// NUL terminated strings, padded to a whole number of words so that the
+// tables after them stay aligned
+fn strings(strings: &[&str]) -> Data {
+    let mut bytes = vec![];
+    for s in strings {
+        bytes.extend_from_slice(s.as_bytes());
+        bytes.push(0);
+    }
+    bytes.resize((bytes.len() + 7) / 8 * 8, 0);
+    Data::Bytes(bytes)
+}
 
-    let (defs_instrs, main_instrs) = compile_program(&p);
-    let defs_asm = instrs_to_string(defs_instrs);
-    let main_asm = instrs_to_string(main_instrs);
+// The data the runtime uses to report where an error happened:
+// the source file name, and a (line, column) pair for each site id
+fn site_table(file: &str, sites: &[Span]) -> Vec<Data> {
+    let mut data = vec![
+        Data::Label("snek_source_file".to_string()),
+        strings(&[file]),
+        Data::Label("snek_site_table".to_string()),
+    ];
+    for span in sites {
+        data.push(Data::Quad(span.line as i64));
+        data.push(Data::Quad(span.col as i64));
+    }
+    data
+}
 
-    let asm_program = format!(
+// The data the runtime uses to print backtraces, with code addresses as
+// offsets from our_code_starts_here:
+// the function table, with the start, end, first site id and name of each
+// function, and the return table, with the return address and site id of
+// each call. Both tables start with their number of entries
+fn backtrace_tables(functions: &[Function], returns: &[(String, i64)]) -> Vec<Data> {
+    let offset = |label: &str| Data::Offset(label.to_string(), "our_code_starts_here".to_string());
+    let mut name_offset = 0;
+    let mut data = vec![
+        Data::Label("snek_function_table".to_string()),
+        Data::Quad(functions.len() as i64),
+    ];
+    for f in functions {
+        data.push(offset(&f.start));
+        data.push(offset(&f.end));
+        data.push(Data::Quad(f.first_site));
+        data.push(Data::Quad(name_offset));
+        name_offset += f.name.len() as i64 + 1;
+    }
+    data.push(Data::Label("snek_return_table".to_string()));
+    data.push(Data::Quad(returns.len() as i64));
+    for (label, site) in returns {
+        data.push(offset(label));
+        data.push(Data::Quad(*site));
+    }
+    data.push(Data::Label("snek_function_names".to_string()));
+    data.push(strings(&functions.iter().map(|f| f.name.as_str()).collect::<Vec<_>>()));
+    data
+}
+
+// A compiled program: the code, starting with the error handler and ending
+// with our_code_starts_here, and the read-only data it refers to
+// functions holds the labels each function starts and ends at
+pub struct Asm {
+    pub text: Vec<Instr>,
+    pub data: Vec<Data>,
+    pub functions: Vec<(String, String)>,
+}
+
+// Keeps the line markers that start code on another line than the code
+// before them. Markers with no code after them are dropped
+fn line_markers(instrs: Vec<Instr>) -> Vec<Instr> {
+    let mut out = vec![];
+    let (mut line, mut pending) = (0, None);
+    for i in instrs {
+        match i {
+            Instr::Line(..) => pending = Some(i),
+            Instr::Label(_) => out.push(i),
+            _ => {
+                if let Some(Instr::Line(n, file)) = pending.take() {
+                    if n != line {
+                        line = n;
+                        out.push(Instr::Line(n, file));
+                    }
+                }
+                out.push(i);
+            }
+        }
+    }
+    out
+}
+
+// The callee-saved registers the compiled code uses: rbx for error codes,
+// r12 for the stack limit, r13 for the bottom of the stack, r14 for the end
+// of the heap and r15 for the heap pointer. rbp, which starts the chain of
+// frames, is pushed before them
+const CALLEE_SAVED: [Reg; 5] = [Reg::Rbx, Reg::R12, Reg::R13, Reg::R14, Reg::R15];
+
+pub fn compile_asm(p: &Program) -> Asm {
+    let mut ctx = Context::default();
+    let mut functions = vec![];
+    let (mut defs_instrs, mut main_instrs) = compile_program(p, &mut ctx, &mut functions);
+    let mut text = error_handler();
+    text.append(&mut defs_instrs);
+    text.push(Instr::Label("our_code_starts_here".to_string()));
+    text.push(line(&p.main.span));
+    // Called with the input, the start and the end of the heap, and the
+    // stack limit. Keeps the callee-saved registers it uses. With rbp, that
+    // is 6 words, so rsp stays aligned the way the code expects
+    text.push(Instr::Push(Reg::Rbp));
+    text.push(Instr::Mov(Arg::Reg(Reg::Rbp), Arg::Reg(Reg::Rsp)));
+    for r in CALLEE_SAVED {
+        text.push(Instr::Push(r));
+    }
+    text.push(Instr::Mov(Arg::Reg(Reg::R15), Arg::Reg(Reg::Rsi)));
+    text.push(Instr::Mov(Arg::Reg(Reg::R14), Arg::Reg(Reg::Rdx)));
+    text.push(Instr::Mov(Arg::Reg(Reg::R12), Arg::Reg(Reg::Rcx)));
+    // The bottom of the stack the garbage collector scans
+    text.push(Instr::Mov(Arg::Reg(Reg::R13), Arg::Reg(Reg::Rsp)));
+    text.append(&mut main_instrs);
+    for r in CALLEE_SAVED.iter().rev() {
+        text.push(Instr::Pop(*r));
+    }
+    text.push(Instr::Pop(Reg::Rbp));
+    text.push(Instr::Ret);
+    text.push(Instr::Label("our_code_ends_here".to_string()));
+    let mut data = site_table(&p.main.span.file, &ctx.spans);
+    data.append(&mut backtrace_tables(&functions, &ctx.returns));
+    let functions = functions.into_iter().map(|f| (f.start, f.end)).collect();
+    Asm { text: line_markers(text), data, functions }
+}
+
+// Symbols of the compiled code that the runtime refers to
+pub const GLOBALS: [&str; 6] = [
+    "our_code_starts_here",
+    "snek_source_file",
+    "snek_site_table",
+    "snek_function_table",
+    "snek_return_table",
+    "snek_function_names",
+];
+
+// Functions of the runtime that the compiled code calls
+pub const EXTERNS: [&str; 3] = ["snek_error", "snek_gc", "snek_print"];
+
+// Prints a compiled program as nasm assembly
+pub fn asm_to_string(asm: &Asm) -> String {
+    let globals: Vec<String> = GLOBALS.iter().map(|g| format!("global {}", g)).collect();
+    let externs: Vec<String> = EXTERNS.iter().map(|e| format!("extern {}", e)).collect();
+    format!(
         "
 section .text
-global our_code_starts_here
-extern snek_error
-extern snek_print
 {}
 {}
-our_code_starts_here:
-mov r15, rsi
 {}
-ret
-", prelude, defs_asm, main_asm);
-    asm_program
+
+section .rodata
+{}
+", globals.join("\n"), externs.join("\n"), instrs_to_string(asm.text.clone()), data_to_string(asm.data.clone()))
+}
+
+pub fn compile(p: &Program) -> String {
+    asm_to_string(&compile_asm(p))
 }
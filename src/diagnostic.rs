@@ -0,0 +1,59 @@
+use std::fmt;
+
+use crate::syntax::Span;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+// The class of problem a diagnostic reports
+pub enum ErrorKind {
+    // Unbalanced parentheses or a malformed token
+    Syntax,
+    // A number that does not fit in a snek integer
+    NumberBounds,
+    // A reserved word or malformed name used as an identifier
+    InvalidIdentifier,
+    // A let binding or binding list that is not well formed
+    InvalidBinding,
+    // The same identifier bound twice in one let
+    DuplicateBinding,
+    // A form with the wrong shape or number of arguments
+    InvalidExpression,
+    // A function definition that is not well formed
+    InvalidFunDef,
+    // The same parameter name used twice in one signature
+    DuplicateParam,
+    // The same function name defined twice
+    DuplicateFunction,
+    // A program without a main expression
+    MissingMain,
+    // A top-level form after the main expression
+    ExtraForm,
+    // A variable used where it is not bound
+    UnboundIdentifier,
+    // A call to a function that is not defined
+    UnknownFunction,
+    // A call with the wrong number of arguments
+    ArityMismatch,
+    // input used inside a function body
+    InputInFunction,
+    // break used outside of a loop
+    BreakOutsideLoop,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn new(kind: ErrorKind, message: String, span: Span) -> Diagnostic {
+        Diagnostic { kind, message, span }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: error: {}", self.span, self.message)
+    }
+}
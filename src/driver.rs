@@ -0,0 +1,83 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::compiler::Asm;
+#[cfg(target_os = "macos")]
+use crate::compiler::asm_to_string;
+#[cfg(not(target_os = "macos"))]
+use crate::elf;
+
+// The runtime is compiled along with every program, so the compiler carries its source
+const RUNTIME: &str = include_str!("../runtime/start.rs");
+const RUNTIME_GC: &str = include_str!("../runtime/gc.rs");
+const RUNTIME_ERRORS: &str = include_str!("../runtime/errors.rs");
+const RUNTIME_PRINT: &str = include_str!("../runtime/print.rs");
+
+// A fresh directory for intermediate files, removed when dropped
+pub struct TempDir {
+    pub path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> Result<TempDir, String> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNT.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("snek-{}-{}", std::process::id(), n));
+        fs::create_dir_all(&path).map_err(|e| format!("could not create {}: {}", path.display(), e))?;
+        Ok(TempDir { path })
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+// Runs a tool, turning a failure to start it or a non-zero exit into an error
+fn run_tool(cmd: &mut Command) -> Result<(), String> {
+    let name = cmd.get_program().to_string_lossy().to_string();
+    let output = cmd.output().map_err(|e| format!("could not run {}: {}", name, e))?;
+    if !output.status.success() {
+        return Err(format!("{} failed:\n{}", name, String::from_utf8_lossy(&output.stderr)));
+    }
+    Ok(())
+}
+
+// Writes the static library libour_code.a that the runtime links against
+#[cfg(not(target_os = "macos"))]
+fn write_lib(asm: &Asm, dir: &Path) -> Result<(), String> {
+    let lib_path = dir.join("libour_code.a");
+    let lib = elf::archive("our_code.o", &elf::object(asm));
+    fs::write(&lib_path, lib).map_err(|e| format!("could not write {}: {}", lib_path.display(), e))
+}
+
+// There is no Mach-O writer, so on macOS the assembly goes through nasm and ar
+#[cfg(target_os = "macos")]
+fn write_lib(asm: &Asm, dir: &Path) -> Result<(), String> {
+    let asm_path = dir.join("our_code.s");
+    let obj_path = dir.join("our_code.o");
+    fs::write(&asm_path, asm_to_string(asm))
+        .map_err(|e| format!("could not write {}: {}", asm_path.display(), e))?;
+    // -g turns the %line directives into line info for debuggers
+    run_tool(Command::new("nasm").args(["-f", "macho64", "-g"]).arg(&asm_path).arg("-o").arg(&obj_path))?;
+    run_tool(Command::new("ar").arg("rcs").arg(dir.join("libour_code.a")).arg(&obj_path))
+}
+
+// Links the compiled program with the runtime into the executable out
+pub fn build(asm: &Asm, out: &Path) -> Result<(), String> {
+    let dir = TempDir::new()?;
+    let runtime_path = dir.path.join("start.rs");
+    for (name, source) in [("start.rs", RUNTIME), ("gc.rs", RUNTIME_GC), ("errors.rs", RUNTIME_ERRORS), ("print.rs", RUNTIME_PRINT)] {
+        let path = dir.path.join(name);
+        fs::write(&path, source).map_err(|e| format!("could not write {}: {}", path.display(), e))?;
+    }
+    write_lib(asm, &dir.path)?;
+    let mut rustc = Command::new("rustc");
+    if cfg!(target_os = "macos") {
+        rustc.arg("--target").arg("x86_64-apple-darwin");
+    }
+    run_tool(rustc.arg("-L").arg(&dir.path).arg(&runtime_path).arg("-o").arg(out))
+}
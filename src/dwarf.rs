@@ -0,0 +1,175 @@
+// DWARF debugging information for the compiled code: a compile unit whose
+// line table maps the code back to the snek source, so that debuggers show
+// snek lines and can break on them
+
+use crate::encoder::Code;
+
+// The section a field of the debugging information points into
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    Text,
+    DebugAbbrev,
+    DebugLine,
+}
+
+// A field the linker fills in with the address of target + addend, 8 bytes
+// wide for code addresses and 4 bytes wide for offsets into other sections
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DebugReloc {
+    pub offset: usize,
+    pub target: Target,
+    pub addend: i64,
+}
+
+// The contents of .debug_abbrev, .debug_info and .debug_line
+#[derive(Default)]
+pub struct Debug {
+    pub abbrev: Vec<u8>,
+    pub info: Vec<u8>,
+    pub info_relocs: Vec<DebugReloc>,
+    pub line: Vec<u8>,
+    pub line_relocs: Vec<DebugReloc>,
+}
+
+const DW_TAG_COMPILE_UNIT: u8 = 0x11;
+const DW_AT_NAME: u8 = 0x03;
+const DW_AT_STMT_LIST: u8 = 0x10;
+const DW_AT_LOW_PC: u8 = 0x11;
+const DW_AT_HIGH_PC: u8 = 0x12;
+const DW_AT_LANGUAGE: u8 = 0x13;
+const DW_AT_COMP_DIR: u8 = 0x1B;
+const DW_AT_PRODUCER: u8 = 0x25;
+const DW_FORM_ADDR: u8 = 0x01;
+const DW_FORM_DATA2: u8 = 0x05;
+const DW_FORM_DATA8: u8 = 0x07;
+const DW_FORM_STRING: u8 = 0x08;
+const DW_FORM_SEC_OFFSET: u8 = 0x17;
+// What nasm reports for assembly
+const DW_LANG_MIPS_ASSEMBLER: u16 = 0x8001;
+
+const DW_LNS_COPY: u8 = 0x01;
+const DW_LNS_ADVANCE_PC: u8 = 0x02;
+const DW_LNS_ADVANCE_LINE: u8 = 0x03;
+const DW_LNE_END_SEQUENCE: u8 = 0x01;
+const DW_LNE_SET_ADDRESS: u8 = 0x02;
+
+fn uleb(out: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7F) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut n: i64) {
+    loop {
+        let byte = (n & 0x7F) as u8;
+        n >>= 7;
+        // Done once the rest is all sign bits, including the top bit of byte
+        if (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(s.as_bytes());
+    out.push(0);
+}
+
+// Prepends the 32-bit length of a unit
+fn unit(contents: Vec<u8>) -> Vec<u8> {
+    let mut out = (contents.len() as u32).to_le_bytes().to_vec();
+    out.extend(contents);
+    out
+}
+
+// The line table of version 4, with a row wherever text.lines starts a line,
+// using only the standard opcodes
+fn line_table(text: &Code, file: &str) -> (Vec<u8>, Vec<DebugReloc>) {
+    // Minimum instruction length, operations per instruction, is_stmt,
+    // line base, line range and opcode base, with the operand counts of
+    // the standard opcodes
+    let mut header = vec![1, 1, 1, -5i8 as u8, 14, 13];
+    header.extend_from_slice(&[0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1]);
+    // No include directories, and a single file in the compilation directory
+    header.push(0);
+    string(&mut header, file);
+    header.extend_from_slice(&[0, 0, 0, 0]);
+
+    let mut program = vec![0, 9, DW_LNE_SET_ADDRESS];
+    let address_offset = program.len();
+    program.extend_from_slice(&0u64.to_le_bytes());
+    let (mut address, mut line) = (0, 1);
+    for (offset, new_line, _) in &text.lines {
+        if *offset > address {
+            program.push(DW_LNS_ADVANCE_PC);
+            uleb(&mut program, (offset - address) as u64);
+            address = *offset;
+        }
+        if *new_line != line {
+            program.push(DW_LNS_ADVANCE_LINE);
+            sleb(&mut program, *new_line as i64 - line as i64);
+            line = *new_line;
+        }
+        program.push(DW_LNS_COPY);
+    }
+    program.push(DW_LNS_ADVANCE_PC);
+    uleb(&mut program, (text.bytes.len() - address) as u64);
+    program.extend_from_slice(&[0, 1, DW_LNE_END_SEQUENCE]);
+
+    let mut contents = 4u16.to_le_bytes().to_vec();
+    contents.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    contents.extend(header);
+    let program_start = 4 + contents.len();
+    contents.extend(program);
+    let reloc = DebugReloc { offset: program_start + address_offset, target: Target::Text, addend: 0 };
+    (unit(contents), vec![reloc])
+}
+
+// The debugging information of text, or None if it has no line markers
+// comp_dir is the directory the source file name is relative to
+pub fn debug_sections(text: &Code, comp_dir: &str) -> Option<Debug> {
+    let file = text.lines.first()?.2.clone();
+    let (line, line_relocs) = line_table(text, &file);
+
+    // A compile unit with no children, covering all of the code
+    let mut abbrev = vec![1, DW_TAG_COMPILE_UNIT, 0];
+    for (at, form) in [
+        (DW_AT_PRODUCER, DW_FORM_STRING),
+        (DW_AT_LANGUAGE, DW_FORM_DATA2),
+        (DW_AT_NAME, DW_FORM_STRING),
+        (DW_AT_COMP_DIR, DW_FORM_STRING),
+        (DW_AT_STMT_LIST, DW_FORM_SEC_OFFSET),
+        (DW_AT_LOW_PC, DW_FORM_ADDR),
+        (DW_AT_HIGH_PC, DW_FORM_DATA8),
+    ] {
+        abbrev.extend_from_slice(&[at, form]);
+    }
+    abbrev.extend_from_slice(&[0, 0, 0]);
+
+    let mut info = 4u16.to_le_bytes().to_vec();
+    let mut info_relocs = vec![DebugReloc { offset: 4 + info.len(), target: Target::DebugAbbrev, addend: 0 }];
+    info.extend_from_slice(&0u32.to_le_bytes());
+    // The size of an address, then the compile unit with abbreviation 1
+    info.push(8);
+    info.push(1);
+    string(&mut info, "snek");
+    info.extend_from_slice(&DW_LANG_MIPS_ASSEMBLER.to_le_bytes());
+    string(&mut info, &file);
+    string(&mut info, comp_dir);
+    info_relocs.push(DebugReloc { offset: 4 + info.len(), target: Target::DebugLine, addend: 0 });
+    info.extend_from_slice(&0u32.to_le_bytes());
+    info_relocs.push(DebugReloc { offset: 4 + info.len(), target: Target::Text, addend: 0 });
+    info.extend_from_slice(&0u64.to_le_bytes());
+    // high_pc as data is the size of the code
+    info.extend_from_slice(&(text.bytes.len() as u64).to_le_bytes());
+
+    Some(Debug { abbrev, info: unit(info), info_relocs, line, line_relocs })
+}
@@ -0,0 +1,291 @@
+use crate::compiler::{Asm, GLOBALS};
+use crate::dwarf::{debug_sections, DebugReloc, Target};
+use crate::encoder::*;
+
+// Section indices, in the order the headers are written
+const TEXT: u16 = 1;
+const RODATA: u16 = 2;
+const SYMTAB: u32 = 3;
+const STRTAB: u32 = 4;
+const DEBUG_ABBREV: u16 = 6;
+const DEBUG_INFO: u16 = 7;
+const DEBUG_LINE: u16 = 9;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+const R_X86_64_64: u64 = 1;
+const R_X86_64_PLT32: u64 = 4;
+const R_X86_64_32: u64 = 10;
+
+// A table of NUL terminated names, starting with the empty name
+struct Strings(Vec<u8>);
+
+impl Strings {
+    fn new() -> Strings {
+        Strings(vec![0])
+    }
+
+    fn add(&mut self, s: &str) -> u32 {
+        let offset = self.0.len() as u32;
+        self.0.extend_from_slice(s.as_bytes());
+        self.0.push(0);
+        offset
+    }
+}
+
+struct Symbol {
+    name: String,
+    bind: u8,
+    kind: u8,
+    section: u16,
+    value: u64,
+    size: u64,
+}
+
+struct Section {
+    name: &'static str,
+    kind: u32,
+    flags: u64,
+    data: Vec<u8>,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+impl Section {
+    fn new(name: &'static str, kind: u32, flags: u64, data: Vec<u8>, align: u64) -> Section {
+        Section { name, kind, flags, data, link: 0, info: 0, align, entsize: 0 }
+    }
+
+    // A table of entries about the section at index link
+    fn table(name: &'static str, kind: u32, data: Vec<u8>, link: u32, info: u32) -> Section {
+        Section { link, info, entsize: 24, ..Section::new(name, kind, 0, data, 8) }
+    }
+}
+
+// The symbols of the object, locals first as ELF requires
+// Functions get their size, so that debuggers know where they end
+// Returns them along with the number of locals
+fn symbols(text: &Code, rodata: &Code, functions: &[(String, String)]) -> (Vec<Symbol>, usize) {
+    let symbol = |name: &str, bind, kind, section, value| Symbol {
+        name: name.to_string(),
+        bind,
+        kind,
+        section,
+        value,
+        size: 0,
+    };
+    let mut locals = vec![
+        symbol("", STB_LOCAL, STT_NOTYPE, 0, 0),
+        symbol("", STB_LOCAL, STT_SECTION, TEXT, 0),
+        symbol("", STB_LOCAL, STT_SECTION, RODATA, 0),
+        symbol("", STB_LOCAL, STT_SECTION, DEBUG_ABBREV, 0),
+        symbol("", STB_LOCAL, STT_SECTION, DEBUG_LINE, 0),
+    ];
+    let mut globals = vec![];
+    for (code, section) in [(text, TEXT), (rodata, RODATA)] {
+        // Sorted by address, so that the output does not depend on hashing
+        let mut labels: Vec<(&String, &usize)> = code.labels.iter().collect();
+        labels.sort_by_key(|&(name, offset)| (*offset, name.clone()));
+        for (name, offset) in labels {
+            let global = GLOBALS.contains(&name.as_str());
+            let bind = if global { STB_GLOBAL } else { STB_LOCAL };
+            let mut s = symbol(name, bind, STT_NOTYPE, section, *offset as u64);
+            if let Some((_, end)) = functions.iter().find(|(start, _)| start == name) {
+                s.kind = STT_FUNC;
+                s.size = (text.labels[end] - offset) as u64;
+            }
+            if global {
+                globals.push(s);
+            } else {
+                locals.push(s);
+            }
+        }
+    }
+    // Whatever the code refers to but does not define comes from the runtime
+    for reloc in &text.relocs {
+        if !globals.iter().any(|s| s.name == reloc.symbol) {
+            globals.push(symbol(&reloc.symbol, STB_GLOBAL, STT_NOTYPE, 0, 0));
+        }
+    }
+    let n_locals = locals.len();
+    locals.append(&mut globals);
+    (locals, n_locals)
+}
+
+// The entries of a relocation section for the debugging information, which
+// refers to the code and to other debugging sections by their section symbols
+fn debug_relocs(symbols: &[Symbol], relocs: &[DebugReloc]) -> Vec<u8> {
+    let mut rela = vec![];
+    for reloc in relocs {
+        let (section, kind) = match reloc.target {
+            Target::Text => (TEXT, R_X86_64_64),
+            Target::DebugAbbrev => (DEBUG_ABBREV, R_X86_64_32),
+            Target::DebugLine => (DEBUG_LINE, R_X86_64_32),
+        };
+        let sym = symbols.iter().position(|s| s.kind == STT_SECTION && s.section == section).unwrap() as u64;
+        rela.extend_from_slice(&(reloc.offset as u64).to_le_bytes());
+        rela.extend_from_slice(&(sym << 32 | kind).to_le_bytes());
+        rela.extend_from_slice(&reloc.addend.to_le_bytes());
+    }
+    rela
+}
+
+fn pad(out: &mut Vec<u8>, align: usize) {
+    while out.len() % align != 0 {
+        out.push(0);
+    }
+}
+
+// Writes the compiled program as an ELF64 relocatable object for x86-64,
+// the same kind of file nasm -f elf64 produces
+pub fn object(asm: &Asm) -> Vec<u8> {
+    let text = encode(&asm.text);
+    let rodata = encode_data(&asm.data, &text);
+    let (symbols, n_locals) = symbols(&text, &rodata, &asm.functions);
+    // Source file names are relative to where the compiler runs
+    let comp_dir = std::env::current_dir().map(|d| d.display().to_string()).unwrap_or_default();
+    let debug = debug_sections(&text, &comp_dir).unwrap_or_default();
+
+    let mut strtab = Strings::new();
+    let mut symtab = vec![];
+    for s in &symbols {
+        let name = if s.name.is_empty() { 0 } else { strtab.add(&s.name) };
+        symtab.extend_from_slice(&name.to_le_bytes());
+        symtab.push(s.bind << 4 | s.kind);
+        symtab.push(0);
+        symtab.extend_from_slice(&s.section.to_le_bytes());
+        symtab.extend_from_slice(&s.value.to_le_bytes());
+        symtab.extend_from_slice(&s.size.to_le_bytes());
+    }
+
+    let mut rela = vec![];
+    for reloc in &text.relocs {
+        let sym = symbols.iter().position(|s| s.name == reloc.symbol).unwrap() as u64;
+        rela.extend_from_slice(&(reloc.offset as u64).to_le_bytes());
+        rela.extend_from_slice(&(sym << 32 | R_X86_64_PLT32).to_le_bytes());
+        rela.extend_from_slice(&reloc.addend.to_le_bytes());
+    }
+
+    let relocations = |name, data, section: u16| {
+        let mut rela = Section::table(name, SHT_RELA, data, SYMTAB, section as u32);
+        rela.flags = SHF_INFO_LINK;
+        rela
+    };
+    let mut sections = vec![
+        Section::new(".text", SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR, text.bytes, 16),
+        Section::new(".rodata", SHT_PROGBITS, SHF_ALLOC, rodata.bytes, 8),
+        Section::table(".symtab", SHT_SYMTAB, symtab, STRTAB, n_locals as u32),
+        Section::new(".strtab", SHT_STRTAB, 0, strtab.0, 1),
+        relocations(".rela.text", rela, TEXT),
+        Section::new(".debug_abbrev", SHT_PROGBITS, 0, debug.abbrev, 1),
+        Section::new(".debug_info", SHT_PROGBITS, 0, debug.info, 1),
+        relocations(".rela.debug_info", debug_relocs(&symbols, &debug.info_relocs), DEBUG_INFO),
+        Section::new(".debug_line", SHT_PROGBITS, 0, debug.line, 1),
+        relocations(".rela.debug_line", debug_relocs(&symbols, &debug.line_relocs), DEBUG_LINE),
+        // Without this, linkers assume the code needs an executable stack
+        Section::new(".note.GNU-stack", SHT_PROGBITS, 0, vec![], 1),
+    ];
+    let mut shstrtab = Strings::new();
+    let mut names: Vec<u32> = sections.iter().map(|s| shstrtab.add(s.name)).collect();
+    names.push(shstrtab.add(".shstrtab"));
+    sections.push(Section::new(".shstrtab", SHT_STRTAB, 0, shstrtab.0, 1));
+
+    // The header, then the contents of each section, then the section headers
+    let mut out = vec![0; 64];
+    let mut offsets = vec![];
+    for s in &sections {
+        pad(&mut out, s.align as usize);
+        offsets.push(out.len() as u64);
+        out.extend_from_slice(&s.data);
+    }
+    pad(&mut out, 8);
+    let shoff = out.len() as u64;
+    out.extend_from_slice(&[0; 64]);
+    for ((s, name), offset) in sections.iter().zip(names).zip(offsets) {
+        out.extend_from_slice(&name.to_le_bytes());
+        out.extend_from_slice(&s.kind.to_le_bytes());
+        out.extend_from_slice(&s.flags.to_le_bytes());
+        out.extend_from_slice(&0u64.to_le_bytes());
+        out.extend_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(&(s.data.len() as u64).to_le_bytes());
+        out.extend_from_slice(&s.link.to_le_bytes());
+        out.extend_from_slice(&s.info.to_le_bytes());
+        out.extend_from_slice(&s.align.to_le_bytes());
+        out.extend_from_slice(&s.entsize.to_le_bytes());
+    }
+
+    let mut header = vec![0x7F, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    header.extend_from_slice(&1u16.to_le_bytes()); // relocatable
+    header.extend_from_slice(&62u16.to_le_bytes()); // x86-64
+    header.extend_from_slice(&1u32.to_le_bytes());
+    header.extend_from_slice(&0u64.to_le_bytes()); // no entry point
+    header.extend_from_slice(&0u64.to_le_bytes()); // no program headers
+    header.extend_from_slice(&shoff.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&64u16.to_le_bytes());
+    header.extend_from_slice(&0u16.to_le_bytes());
+    header.extend_from_slice(&0u16.to_le_bytes());
+    header.extend_from_slice(&64u16.to_le_bytes());
+    header.extend_from_slice(&(sections.len() as u16 + 1).to_le_bytes());
+    header.extend_from_slice(&(sections.len() as u16).to_le_bytes());
+    out[..64].copy_from_slice(&header);
+    out
+}
+
+// Pads an ar header field with spaces
+fn ar_field(out: &mut Vec<u8>, s: &str, width: usize) {
+    out.extend_from_slice(s.as_bytes());
+    out.resize(out.len() + width - s.len(), b' ');
+}
+
+fn ar_member(out: &mut Vec<u8>, name: &str, data: &[u8]) {
+    ar_field(out, name, 16);
+    ar_field(out, "0", 12);
+    ar_field(out, "0", 6);
+    ar_field(out, "0", 6);
+    ar_field(out, "644", 8);
+    ar_field(out, &data.len().to_string(), 10);
+    out.extend_from_slice(b"`\n");
+    out.extend_from_slice(data);
+    if out.len() % 2 == 1 {
+        out.push(b'\n');
+    }
+}
+
+// Wraps an object in a static library, with the symbol index linkers
+// need to find GLOBALS in it, like ar rcs does
+pub fn archive(name: &str, object: &[u8]) -> Vec<u8> {
+    let mut names = vec![];
+    for g in GLOBALS {
+        names.extend_from_slice(g.as_bytes());
+        names.push(0);
+    }
+    let index_len = 4 + 4 * GLOBALS.len() + names.len();
+    // The object comes right after the index member
+    let object_offset = (8 + 60 + index_len + index_len % 2) as u32;
+    let mut index = (GLOBALS.len() as u32).to_be_bytes().to_vec();
+    for _ in GLOBALS {
+        index.extend_from_slice(&object_offset.to_be_bytes());
+    }
+    index.append(&mut names);
+
+    let mut out = b"!<arch>\n".to_vec();
+    ar_member(&mut out, "/", &index);
+    ar_member(&mut out, &format!("{}/", name), object);
+    out
+}
@@ -0,0 +1,420 @@
+use std::collections::HashMap;
+
+use crate::asm::*;
+
+// A place in the code that refers to a symbol defined outside of it
+// The 4 bytes at offset must become symbol + addend - the address of the field
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reloc {
+    pub offset: usize,
+    pub symbol: String,
+    pub addend: i64,
+}
+
+// Machine code, the offset of each label in it, and the fields left for the linker
+// lines holds the source line and file of the code from each offset on, in order
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Code {
+    pub bytes: Vec<u8>,
+    pub labels: HashMap<String, usize>,
+    pub relocs: Vec<Reloc>,
+    pub lines: Vec<(usize, usize, String)>,
+}
+
+fn reg_num(r: Reg) -> u8 {
+    match r {
+        Reg::Rax => 0,
+        Reg::Rcx => 1,
+        Reg::Rdx => 2,
+        Reg::Rbx => 3,
+        Reg::Rsp => 4,
+        Reg::Rbp => 5,
+        Reg::Rsi => 6,
+        Reg::Rdi => 7,
+        Reg::R8 => 8,
+        Reg::R12 => 12,
+        Reg::R13 => 13,
+        Reg::R14 => 14,
+        Reg::R15 => 15,
+    }
+}
+
+fn fits_i8(n: i64) -> bool {
+    i8::try_from(n).is_ok()
+}
+
+fn imm32(n: i64) -> [u8; 4] {
+    match i32::try_from(n) {
+        Ok(n) => n.to_le_bytes(),
+        Err(_) => panic!("Immediate does not fit in 32 bits: {}", n),
+    }
+}
+
+// The condition codes of jcc and cmovcc
+fn cond(i: &Instr) -> u8 {
+    match i {
+        Instr::Jo(_) => 0x0,
+        Instr::Jb(_) => 0x2,
+        Instr::Je(_) | Instr::Cmove(..) => 0x4,
+        Instr::Jne(_) => 0x5,
+        Instr::Jl(_) | Instr::Cmovl(..) => 0xC,
+        Instr::Jge(_) | Instr::Cmovge(..) => 0xD,
+        Instr::Jle(_) | Instr::Cmovle(..) => 0xE,
+        Instr::Jg(_) | Instr::Cmovg(..) => 0xF,
+        _ => panic!("Not a conditional instruction: {:?}", i),
+    }
+}
+
+// The operand that goes in the ModRM r/m field
+enum Rm<'a> {
+    Reg(Reg),
+    Mem(&'a MemAddr),
+}
+
+// Emits REX.W, opcode, and ModRM (with SIB and displacement) for 64-bit operands
+// reg is either a register number or the opcode extension of /n forms
+fn modrm(out: &mut Vec<u8>, opcode: &[u8], reg: u8, rm: Rm) {
+    let (x, b) = match rm {
+        Rm::Reg(r) => (0, reg_num(r) >> 3),
+        Rm::Mem(MemAddr::MemAddr { base, index, .. }) => {
+            (index.map_or(0, |i| reg_num(i) >> 3), reg_num(*base) >> 3)
+        }
+    };
+    out.push(0x48 | (reg >> 3) << 2 | x << 1 | b);
+    out.extend_from_slice(opcode);
+    let reg = (reg & 7) << 3;
+    let MemAddr::MemAddr { base, index, scale, disp } = match rm {
+        Rm::Reg(r) => {
+            out.push(0xC0 | reg | reg_num(r) & 7);
+            return;
+        }
+        Rm::Mem(m) => m,
+    };
+    let base = reg_num(*base) & 7;
+    // rbp and r13 have no form without a displacement
+    let md = if *disp == 0 && base != 5 {
+        0x00
+    } else if fits_i8(*disp) {
+        0x40
+    } else {
+        0x80
+    };
+    match index {
+        Some(index) => {
+            let scale = match scale.unwrap_or(1) {
+                1 => 0,
+                2 => 1,
+                4 => 2,
+                8 => 3,
+                s => panic!("Invalid scale: {}", s),
+            };
+            if *index == Reg::Rsp {
+                panic!("rsp cannot be an index");
+            }
+            out.push(md | reg | 4);
+            out.push(scale << 6 | (reg_num(*index) & 7) << 3 | base);
+        }
+        // rsp and r12 as base always need a SIB byte
+        None if base == 4 => {
+            out.push(md | reg | 4);
+            out.push(0x24);
+        }
+        None => out.push(md | reg | base),
+    }
+    match md {
+        0x40 => out.push(*disp as i8 as u8),
+        0x80 => out.extend_from_slice(&imm32(*disp)),
+        _ => {}
+    }
+}
+
+fn rm_of(a: &Arg) -> Rm<'_> {
+    match a {
+        Arg::Reg(r) => Rm::Reg(*r),
+        Arg::Mem(m) => Rm::Mem(m),
+        Arg::Imm(_) => panic!("Expected a register or memory operand"),
+    }
+}
+
+// add, or, and, sub, xor and cmp share their encodings, told apart by n
+fn alu(out: &mut Vec<u8>, n: u8, dst: &Arg, src: &Arg) {
+    match (dst, src) {
+        (Arg::Imm(_), _) => panic!("Cannot use an immediate as a destination"),
+        (Arg::Mem(_), Arg::Mem(_)) => panic!("Cannot use two memory operands"),
+        (_, Arg::Reg(r)) => modrm(out, &[n * 8 + 1], reg_num(*r), rm_of(dst)),
+        (Arg::Reg(r), Arg::Mem(m)) => modrm(out, &[n * 8 + 3], reg_num(*r), Rm::Mem(m)),
+        (_, Arg::Imm(i)) if fits_i8(*i) => {
+            modrm(out, &[0x83], n, rm_of(dst));
+            out.push(*i as i8 as u8);
+        }
+        (Arg::Reg(Reg::Rax), Arg::Imm(i)) => {
+            out.extend_from_slice(&[0x48, n * 8 + 5]);
+            out.extend_from_slice(&imm32(*i));
+        }
+        (_, Arg::Imm(i)) => {
+            modrm(out, &[0x81], n, rm_of(dst));
+            out.extend_from_slice(&imm32(*i));
+        }
+    }
+}
+
+fn mov(out: &mut Vec<u8>, dst: &Arg, src: &Arg) {
+    match (dst, src) {
+        (Arg::Imm(_), _) => panic!("Cannot use an immediate as a destination"),
+        (Arg::Mem(_), Arg::Mem(_)) => panic!("Cannot use two memory operands"),
+        (_, Arg::Reg(r)) => modrm(out, &[0x89], reg_num(*r), rm_of(dst)),
+        (Arg::Reg(r), Arg::Mem(m)) => modrm(out, &[0x8B], reg_num(*r), Rm::Mem(m)),
+        // Like nasm, use the shortest form: a 32-bit mov zero-extends,
+        // a sign-extended imm32, or the full imm64
+        (Arg::Reg(r), Arg::Imm(i)) if u32::try_from(*i).is_ok() => {
+            let r = reg_num(*r);
+            if r >= 8 {
+                out.push(0x41);
+            }
+            out.push(0xB8 + (r & 7));
+            out.extend_from_slice(&(*i as u32).to_le_bytes());
+        }
+        (Arg::Reg(r), Arg::Imm(i)) if i32::try_from(*i).is_err() => {
+            let r = reg_num(*r);
+            out.extend_from_slice(&[0x48 | r >> 3, 0xB8 + (r & 7)]);
+            out.extend_from_slice(&i.to_le_bytes());
+        }
+        (_, Arg::Imm(i)) => {
+            modrm(out, &[0xC7], 0, rm_of(dst));
+            out.extend_from_slice(&imm32(*i));
+        }
+    }
+}
+
+fn test(out: &mut Vec<u8>, dst: &Arg, src: &Arg) {
+    match (dst, src) {
+        (Arg::Imm(_), _) => panic!("Cannot use an immediate as a destination"),
+        (Arg::Mem(_), Arg::Mem(_)) => panic!("Cannot use two memory operands"),
+        (_, Arg::Reg(r)) => modrm(out, &[0x85], reg_num(*r), rm_of(dst)),
+        (Arg::Reg(r), Arg::Mem(m)) => modrm(out, &[0x85], reg_num(*r), Rm::Mem(m)),
+        (Arg::Reg(Reg::Rax), Arg::Imm(i)) => {
+            out.extend_from_slice(&[0x48, 0xA9]);
+            out.extend_from_slice(&imm32(*i));
+        }
+        (_, Arg::Imm(i)) => {
+            modrm(out, &[0xF7], 0, rm_of(dst));
+            out.extend_from_slice(&imm32(*i));
+        }
+    }
+}
+
+fn imul(out: &mut Vec<u8>, dst: &Arg, src: &Arg) {
+    let r = match dst {
+        Arg::Reg(r) => reg_num(*r),
+        _ => panic!("imul needs a register destination"),
+    };
+    match src {
+        Arg::Imm(i) if fits_i8(*i) => {
+            modrm(out, &[0x6B], r, rm_of(dst));
+            out.push(*i as i8 as u8);
+        }
+        Arg::Imm(i) => {
+            modrm(out, &[0x69], r, rm_of(dst));
+            out.extend_from_slice(&imm32(*i));
+        }
+        _ => modrm(out, &[0x0F, 0xAF], r, rm_of(src)),
+    }
+}
+
+// shl is /4 and sar is /7
+fn shift(out: &mut Vec<u8>, n: u8, dst: &Arg, src: &Arg) {
+    match src {
+        Arg::Imm(1) => modrm(out, &[0xD1], n, rm_of(dst)),
+        Arg::Imm(i) if (0..64).contains(i) => {
+            modrm(out, &[0xC1], n, rm_of(dst));
+            out.push(*i as u8);
+        }
+        Arg::Reg(Reg::Rcx) => modrm(out, &[0xD3], n, rm_of(dst)),
+        _ => panic!("Invalid shift amount: {:?}", src),
+    }
+}
+
+fn cmov(out: &mut Vec<u8>, cc: u8, dst: &Arg, src: &Arg) {
+    match dst {
+        Arg::Reg(r) => modrm(out, &[0x0F, 0x40 + cc], reg_num(*r), rm_of(src)),
+        _ => panic!("cmov needs a register destination"),
+    }
+}
+
+// Encodes everything but jumps and calls, which depend on where labels are
+// push and pop take the register in the opcode
+fn push_pop(out: &mut Vec<u8>, opcode: u8, r: Reg) {
+    let r = reg_num(r);
+    if r >= 8 {
+        out.push(0x41);
+    }
+    out.push(opcode + (r & 7));
+}
+
+// call is /2 and jmp is /4 of FF, which need no REX.W for a 64-bit register
+fn indirect(out: &mut Vec<u8>, n: u8, r: Reg) {
+    let r = reg_num(r);
+    if r >= 8 {
+        out.push(0x41);
+    }
+    out.extend_from_slice(&[0xFF, 0xC0 | n << 3 | (r & 7)]);
+}
+
+fn encode_plain(out: &mut Vec<u8>, i: &Instr) {
+    match i {
+        Instr::Mov(d, s) => mov(out, d, s),
+        Instr::Add(d, s) => alu(out, 0, d, s),
+        Instr::Or(d, s) => alu(out, 1, d, s),
+        Instr::And(d, s) => alu(out, 4, d, s),
+        Instr::Sub(d, s) => alu(out, 5, d, s),
+        Instr::Xor(d, s) => alu(out, 6, d, s),
+        Instr::Cmp(d, s) => alu(out, 7, d, s),
+        Instr::Test(d, s) => test(out, d, s),
+        Instr::Imul(d, s) => imul(out, d, s),
+        Instr::Shl(d, s) => shift(out, 4, d, s),
+        Instr::Sar(d, s) => shift(out, 7, d, s),
+        Instr::Cmove(d, s) | Instr::Cmovl(d, s) | Instr::Cmovle(d, s) | Instr::Cmovg(d, s)
+        | Instr::Cmovge(d, s) => cmov(out, cond(i), d, s),
+        Instr::Ret => out.push(0xC3),
+        Instr::Push(r) => push_pop(out, 0x50, *r),
+        Instr::Pop(r) => push_pop(out, 0x58, *r),
+        Instr::CallReg(r) => indirect(out, 2, *r),
+        Instr::JmpReg(r) => indirect(out, 4, *r),
+        Instr::Label(_) | Instr::Line(..) | Instr::Jmp(_) | Instr::Je(_) | Instr::Jne(_) | Instr::Jo(_) | Instr::Jl(_)
+        | Instr::Jle(_) | Instr::Jg(_) | Instr::Jge(_) | Instr::Jb(_) | Instr::Call(_) | Instr::Lea(..) => {
+            unreachable!()
+        }
+    }
+}
+
+// Calls and lea take the address of their label relative to the next instruction
+fn call_target(i: &Instr) -> Option<&String> {
+    match i {
+        Instr::Call(l) | Instr::Lea(_, l) => Some(l),
+        _ => None,
+    }
+}
+
+fn jump_target(i: &Instr) -> Option<&String> {
+    match i {
+        Instr::Jmp(l) | Instr::Je(l) | Instr::Jne(l) | Instr::Jo(l) | Instr::Jl(l) | Instr::Jle(l)
+        | Instr::Jg(l) | Instr::Jge(l) | Instr::Jb(l) => Some(l),
+        _ => None,
+    }
+}
+
+// A field to fill in once every label has an offset
+struct Fixup {
+    offset: usize,
+    size: usize,
+    label: String,
+}
+
+// Lays out the code once, with the jumps at the given indices in their long form
+// Also returns where each instruction ends
+fn layout(instrs: &[Instr], long: &[bool]) -> (Code, Vec<usize>) {
+    let mut code = Code::default();
+    let mut ends = vec![];
+    let mut fixups = vec![];
+    for (idx, i) in instrs.iter().enumerate() {
+        let out = &mut code.bytes;
+        let (opcode, size): (&[u8], usize) = match i {
+            Instr::Label(l) => {
+                if code.labels.insert(l.clone(), out.len()).is_some() {
+                    panic!("Label defined twice: {}", l);
+                }
+                (&[], 0)
+            }
+            Instr::Line(line, file) => {
+                code.lines.push((out.len(), *line, file.clone()));
+                (&[], 0)
+            }
+            Instr::Call(_) => (&[0xE8], 4),
+            // lea r, [rel label] is REX.W 8D with a RIP-relative ModRM
+            Instr::Lea(r, _) => {
+                let r = reg_num(*r);
+                out.extend_from_slice(&[0x48 | (r >> 3) << 2, 0x8D, 0x05 | (r & 7) << 3]);
+                (&[], 4)
+            }
+            Instr::Jmp(_) if long[idx] => (&[0xE9], 4),
+            Instr::Jmp(_) => (&[0xEB], 1),
+            _ if jump_target(i).is_some() && long[idx] => (&[0x0F, 0x80 + cond(i)], 4),
+            _ if jump_target(i).is_some() => (&[0x70 + cond(i)], 1),
+            _ => {
+                encode_plain(out, i);
+                (&[], 0)
+            }
+        };
+        out.extend_from_slice(opcode);
+        if let Some(l) = call_target(i).or(jump_target(i)) {
+            fixups.push(Fixup { offset: out.len(), size, label: l.clone() });
+            out.resize(out.len() + size, 0);
+        }
+        ends.push(out.len());
+    }
+    // Fields are relative to the end of their instruction, which is where they end
+    for Fixup { offset, size, label } in fixups {
+        match code.labels.get(&label) {
+            Some(&target) => {
+                let rel = target as i64 - (offset + size) as i64;
+                if size == 1 {
+                    // Short jumps that do not reach are grown by encode
+                    code.bytes[offset] = rel as i8 as u8;
+                } else {
+                    code.bytes[offset..offset + 4].copy_from_slice(&imm32(rel));
+                }
+            }
+            None if code.bytes[offset - 1] == 0xE8 => {
+                code.relocs.push(Reloc { offset, symbol: label, addend: -4 })
+            }
+            None => panic!("Jump to undefined label: {}", label),
+        }
+    }
+    (code, ends)
+}
+
+// Encodes instrs as x86-64 machine code, choosing the same encodings as nasm
+// Calls to labels that are not defined in instrs are left as relocations
+pub fn encode(instrs: &[Instr]) -> Code {
+    // Start with every jump short, and grow the ones that do not reach
+    // until none change. Growing a jump only moves labels further apart,
+    // so this ends
+    let mut long = vec![false; instrs.len()];
+    loop {
+        let (code, ends) = layout(instrs, &long);
+        let mut grown = false;
+        for (idx, i) in instrs.iter().enumerate() {
+            if let (Some(l), false) = (jump_target(i), long[idx]) {
+                if !fits_i8(code.labels[l] as i64 - ends[idx] as i64) {
+                    long[idx] = true;
+                    grown = true;
+                }
+            }
+        }
+        if !grown {
+            return code;
+        }
+    }
+}
+
+// Lays out the read-only data, recording the offset of each label
+// Offsets are between labels of text, the code the data goes with
+pub fn encode_data(data: &[Data], text: &Code) -> Code {
+    let mut code = Code::default();
+    for d in data {
+        match d {
+            Data::Label(l) => {
+                if code.labels.insert(l.clone(), code.bytes.len()).is_some() {
+                    panic!("Label defined twice: {}", l);
+                }
+            }
+            Data::Quad(n) => code.bytes.extend_from_slice(&n.to_le_bytes()),
+            Data::Offset(label, base) => {
+                let offset = text.labels[label] as i64 - text.labels[base] as i64;
+                code.bytes.extend_from_slice(&offset.to_le_bytes());
+            }
+            Data::Bytes(bytes) => code.bytes.extend_from_slice(bytes),
+        }
+    }
+    code
+}
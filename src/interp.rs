@@ -0,0 +1,559 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::io::Write;
+
+use im::HashMap;
+
+use crate::errors::SnekError;
+use crate::printer::{binop_name, unop_name};
+use crate::syntax::*;
+
+// Snek integers are 63 bits wide
+const MIN_INT: i64 = -(1 << 62);
+const MAX_INT: i64 = (1 << 62) - 1;
+
+// How deeply calls can nest before evaluation reports a stack overflow, the
+// way the compiled code does when it runs out of stack
+const MAX_DEPTH: usize = 10_000;
+
+// The stack evaluation needs to reach MAX_DEPTH in a debug build, for the
+// thread that runs the interpreter
+pub const STACK_SIZE: usize = 256 << 20;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+// A snek value, tuples are indices into the interpreter heap
+// and functions into its closures
+pub enum Val {
+    Num(i64),
+    Bool(bool),
+    Tup(usize),
+    Fun(usize),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+// A runtime error, reported the way snek_error in the runtime reports it
+pub enum RuntimeError {
+    // The operands fill in the message of the error
+    Snek { error: SnekError, operands: Vec<String>, site: Span },
+    // Evaluation took more steps than the limit set with with_step_limit
+    StepLimit { site: Span },
+}
+
+impl RuntimeError {
+    fn snek(error: SnekError, operands: Vec<String>, site: &Span) -> RuntimeError {
+        RuntimeError::Snek { error, operands, site: site.clone() }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::Snek { error, operands, site } => {
+                write!(f, "error {} at {}: {}", error.code(), site, error.message(operands))
+            }
+            RuntimeError::StepLimit { site } => write!(f, "error at {}: step limit exceeded", site),
+        }
+    }
+}
+
+// How evaluating an expression can end, besides producing a value
+// The error is boxed to keep the results of evaluation small
+enum Exit {
+    Break(Val),
+    Error(Box<RuntimeError>),
+}
+
+impl From<RuntimeError> for Exit {
+    fn from(e: RuntimeError) -> Exit {
+        Exit::Error(Box::new(e))
+    }
+}
+
+// Parses the input of a program, the same way the runtime does
+pub fn parse_input(input: &str) -> Result<Val, String> {
+    match input {
+        "true" => Ok(Val::Bool(true)),
+        "false" => Ok(Val::Bool(false)),
+        _ => match input.parse::<i64>() {
+            Ok(n) if (MIN_INT..=MAX_INT).contains(&n) => Ok(Val::Num(n)),
+            Ok(_) => Err(format!("Invalid input: overflow {}", input)),
+            Err(_) => Err(format!("Invalid input: {}", input)),
+        },
+    }
+}
+
+// The value of a lambda or function: its parameters and body, with the
+// values of the variables in scope when it was made
+struct Closure<'a> {
+    params: &'a [String],
+    body: &'a Expr,
+    captured: Vec<(String, Val)>,
+    // Where the function is defined, which reports its stack overflows
+    span: &'a Span,
+}
+
+// A tree-walking evaluator for snek programs
+// Output from print is written to out
+pub struct Interp<'a, W: Write> {
+    funs: HashMap<&'a str, &'a FunDef>,
+    heap: Vec<Vec<Val>>,
+    closures: Vec<Closure<'a>>,
+    input: Val,
+    out: W,
+    // How many more loop iterations and calls can run, if limited
+    steps: Option<u64>,
+    // How many calls are running
+    depth: usize,
+}
+
+impl<'a, W: Write> Interp<'a, W> {
+    pub fn new(p: &'a Program, input: Val, out: W) -> Interp<'a, W> {
+        let funs = p.defs.iter().map(|def| (def.name.as_str(), def)).collect();
+        Interp { funs, heap: vec![], closures: vec![], input, out, steps: None, depth: 0 }
+    }
+
+    // Limits evaluation to a number of loop iterations and calls in total,
+    // for programs that may not terminate
+    pub fn with_step_limit(mut self, steps: u64) -> Interp<'a, W> {
+        self.steps = Some(steps);
+        self
+    }
+
+    fn step(&mut self, site: &Span) -> Result<(), RuntimeError> {
+        match &mut self.steps {
+            Some(0) => Err(RuntimeError::StepLimit { site: site.clone() }),
+            Some(n) => {
+                *n -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Evaluates the body of a function entered with frame, failing like the
+    // compiled code does on entry if calls nest too deeply
+    fn enter(
+        &mut self,
+        body: &'a Expr,
+        env: &HashMap<String, usize>,
+        frame: &mut Vec<Val>,
+        span: &Span,
+    ) -> Result<Val, Exit> {
+        if self.depth == MAX_DEPTH {
+            return Err(RuntimeError::snek(SnekError::StackOverflow, vec![], span).into());
+        }
+        self.depth += 1;
+        let result = self.eval(body, env, frame);
+        self.depth -= 1;
+        result
+    }
+
+    // Formats a value like snek_str in the runtime
+    pub fn val_to_string(&self, v: Val) -> String {
+        self.val_str(v, &mut HashSet::new())
+    }
+
+    fn val_str(&self, v: Val, seen: &mut HashSet<usize>) -> String {
+        match v {
+            Val::Num(n) => n.to_string(),
+            Val::Bool(b) => b.to_string(),
+            Val::Tup(t) if seen.contains(&t) => "(...)".to_string(),
+            Val::Tup(t) => {
+                seen.insert(t);
+                let elems: Vec<String> = self.heap[t].iter().map(|e| self.val_str(*e, seen)).collect();
+                seen.remove(&t);
+                format!("({})", elems.join(", "))
+            }
+            Val::Fun(_) => "<function>".to_string(),
+        }
+    }
+
+    // op is the operation that needs a number, for the error
+    fn num(&self, v: Val, op: &str, site: &Span) -> Result<i64, RuntimeError> {
+        match v {
+            Val::Num(n) => Ok(n),
+            _ => Err(RuntimeError::snek(
+                SnekError::InvalidArith,
+                vec![op.to_string(), self.val_to_string(v)],
+                site,
+            )),
+        }
+    }
+
+    fn tup(&self, v: Val, op: &str, site: &Span) -> Result<usize, RuntimeError> {
+        match v {
+            Val::Tup(t) => Ok(t),
+            _ => Err(RuntimeError::snek(
+                SnekError::NotATuple,
+                vec![op.to_string(), self.val_to_string(v)],
+                site,
+            )),
+        }
+    }
+
+    fn index(&self, t: usize, i: i64, site: &Span) -> Result<usize, RuntimeError> {
+        let len = self.heap[t].len();
+        if i < 0 || i as usize >= len {
+            return Err(RuntimeError::snek(
+                SnekError::IndexOutOfBounds,
+                vec![i.to_string(), len.to_string()],
+                site,
+            ));
+        }
+        Ok(i as usize)
+    }
+
+    fn checked(&self, n: Option<i64>, site: &Span) -> Result<Val, RuntimeError> {
+        match n {
+            Some(n) if (MIN_INT..=MAX_INT).contains(&n) => Ok(Val::Num(n)),
+            _ => Err(RuntimeError::snek(SnekError::Overflow, vec![], site)),
+        }
+    }
+
+    // Calls the function value f, like the compiled code: it must be a
+    // function that takes as many arguments as there are, which are only
+    // evaluated after checking that
+    fn apply(
+        &mut self,
+        f: Val,
+        args: &'a [Expr],
+        env: &HashMap<String, usize>,
+        frame: &mut Vec<Val>,
+        site: &Span,
+    ) -> Result<Val, Exit> {
+        self.step(site)?;
+        let Val::Fun(i) = f else {
+            return Err(RuntimeError::snek(SnekError::NotAFunction, vec![self.val_to_string(f)], site).into());
+        };
+        let (params, body, span) = (self.closures[i].params, self.closures[i].body, self.closures[i].span);
+        if params.len() != args.len() {
+            let operands = vec![params.len().to_string(), args.len().to_string()];
+            return Err(RuntimeError::snek(SnekError::ArityMismatch, operands, site).into());
+        }
+        let mut values = vec![];
+        for arg in args {
+            values.push(self.eval(arg, env, frame)?);
+        }
+        let mut new_env = HashMap::new();
+        let mut new_frame = vec![];
+        let captured = self.closures[i].captured.iter().map(|(id, v)| (id, *v));
+        for (id, v) in captured.chain(params.iter().zip(values)) {
+            new_env.insert(id.clone(), new_frame.len());
+            new_frame.push(v);
+        }
+        match self.enter(body, &new_env, &mut new_frame, span) {
+            Err(Exit::Break(_)) => panic!("break outside of loop in lambda"),
+            result => result,
+        }
+    }
+
+    fn print(&mut self, v: Val) {
+        let s = self.val_to_string(v);
+        writeln!(self.out, "{}", s).expect("could not write output");
+    }
+
+    // env maps variables to their slot in frame, the locals of the current call
+    // Each form with locals of its own is evaluated by a method of its own,
+    // so that the frames of this recursion stay small
+    fn eval(&mut self, e: &'a Expr, env: &HashMap<String, usize>, frame: &mut Vec<Val>) -> Result<Val, Exit> {
+        let site = &e.span;
+        match &e.kind {
+            ExprKind::Number(n) => Ok(Val::Num(*n)),
+            ExprKind::Boolean(b) => Ok(Val::Bool(*b)),
+            ExprKind::Var(id) if id == "input" => Ok(self.input),
+            ExprKind::Var(id) if !env.contains_key(id) => Ok(self.fun_value(id)),
+            ExprKind::Var(id) => Ok(frame[env[id]]),
+            ExprKind::Let(bindings, body) => self.eval_let(bindings, body, env, frame),
+            ExprKind::UnOp(op, e) => self.eval_unop(op, e, env, frame, site),
+            ExprKind::BinOp(op, e1, e2) => self.eval_binop(op, e1, e2, env, frame, site),
+            ExprKind::If(cond, thn, els) => self.eval_if(cond, thn, els, env, frame),
+            ExprKind::Loop(body) => self.eval_loop(body, env, frame, site),
+            ExprKind::Break(e) => match self.eval(e, env, frame) {
+                Ok(v) => Err(Exit::Break(v)),
+                err => err,
+            },
+            ExprKind::Set(id, e) => self.eval_set(id, e, env, frame),
+            ExprKind::Block(es) => self.eval_block(es, env, frame),
+            ExprKind::Print(e) => self.eval_print(e, env, frame),
+            ExprKind::Tup(es) => self.eval_tup(es, env, frame),
+            ExprKind::TupGet(t, i) => self.eval_tup_get(t, i, env, frame, site),
+            ExprKind::TupSet(t, i, e) => self.eval_tup_set(t, i, e, env, frame, site),
+            ExprKind::TupLen(t) => self.eval_tup_len(t, env, frame, site),
+            ExprKind::Call(fname, args) if env.contains_key(fname) => {
+                let f = frame[env[fname]];
+                self.apply(f, args, env, frame, site)
+            }
+            ExprKind::Call(fname, args) => self.call(fname, args, env, frame, site),
+            ExprKind::Lambda(params, body) => Ok(self.lambda(params, body, env, frame, site)),
+            ExprKind::Apply(f, args) => match self.eval(f, env, frame) {
+                Ok(f) => self.apply(f, args, env, frame, site),
+                err => err,
+            },
+            ExprKind::Closure(..) => panic!("Closures are made by closure conversion, which the interpreter does not do"),
+        }
+    }
+
+    // The value of a function of the program
+    fn fun_value(&mut self, name: &str) -> Val {
+        let def = self.funs[name];
+        self.closures.push(Closure { params: &def.params, body: &def.body, captured: vec![], span: &def.span });
+        Val::Fun(self.closures.len() - 1)
+    }
+
+    fn lambda(
+        &mut self,
+        params: &'a [String],
+        body: &'a Expr,
+        env: &HashMap<String, usize>,
+        frame: &[Val],
+        span: &'a Span,
+    ) -> Val {
+        let captured = env.iter().map(|(id, i)| (id.clone(), frame[*i])).collect();
+        self.closures.push(Closure { params, body, captured, span });
+        Val::Fun(self.closures.len() - 1)
+    }
+
+    fn eval_let(
+        &mut self,
+        bindings: &'a [(String, Expr)],
+        body: &'a Expr,
+        env: &HashMap<String, usize>,
+        frame: &mut Vec<Val>,
+    ) -> Result<Val, Exit> {
+        let mut new_env = env.clone();
+        for (id, e) in bindings {
+            let v = self.eval(e, &new_env, frame)?;
+            new_env.insert(id.clone(), frame.len());
+            frame.push(v);
+        }
+        let v = self.eval(body, &new_env, frame);
+        frame.truncate(frame.len() - bindings.len());
+        v
+    }
+
+    fn eval_unop(
+        &mut self,
+        op: &Op1,
+        e: &'a Expr,
+        env: &HashMap<String, usize>,
+        frame: &mut Vec<Val>,
+        site: &Span,
+    ) -> Result<Val, Exit> {
+        let v = self.eval(e, env, frame)?;
+        Ok(self.unop(op, v, site)?)
+    }
+
+    fn unop(&self, op: &Op1, v: Val, site: &Span) -> Result<Val, RuntimeError> {
+        match op {
+            Op1::Add1 => self.checked(self.num(v, unop_name(op), site)?.checked_add(1), site),
+            Op1::Sub1 => self.checked(self.num(v, unop_name(op), site)?.checked_sub(1), site),
+            Op1::IsNum => Ok(Val::Bool(matches!(v, Val::Num(_)))),
+            Op1::IsBool => Ok(Val::Bool(matches!(v, Val::Bool(_)))),
+        }
+    }
+
+    fn eval_binop(
+        &mut self,
+        op: &Op2,
+        e1: &'a Expr,
+        e2: &'a Expr,
+        env: &HashMap<String, usize>,
+        frame: &mut Vec<Val>,
+        site: &Span,
+    ) -> Result<Val, Exit> {
+        let v1 = self.eval(e1, env, frame)?;
+        if let Op2::Equal = op {
+            let v2 = self.eval(e2, env, frame)?;
+            return Ok(self.equal(v1, v2, site)?);
+        }
+        let n1 = self.num(v1, binop_name(op), site)?;
+        let v2 = self.eval(e2, env, frame)?;
+        let n2 = self.num(v2, binop_name(op), site)?;
+        Ok(self.arith(op, n1, n2, site)?)
+    }
+
+    fn equal(&self, v1: Val, v2: Val, site: &Span) -> Result<Val, RuntimeError> {
+        match (v1, v2) {
+            (Val::Num(n1), Val::Num(n2)) => Ok(Val::Bool(n1 == n2)),
+            (Val::Num(_), _) | (_, Val::Num(_)) => {
+                let operands = vec![self.val_to_string(v1), self.val_to_string(v2)];
+                Err(RuntimeError::snek(SnekError::InvalidEqual, operands, site))
+            }
+            (v1, v2) => Ok(Val::Bool(v1 == v2)),
+        }
+    }
+
+    fn arith(&self, op: &Op2, n1: i64, n2: i64, site: &Span) -> Result<Val, RuntimeError> {
+        match op {
+            Op2::Plus => self.checked(n1.checked_add(n2), site),
+            Op2::Minus => self.checked(n1.checked_sub(n2), site),
+            Op2::Times => self.checked(n1.checked_mul(n2), site),
+            Op2::Less => Ok(Val::Bool(n1 < n2)),
+            Op2::LessEqual => Ok(Val::Bool(n1 <= n2)),
+            Op2::Greater => Ok(Val::Bool(n1 > n2)),
+            Op2::GreaterEqual => Ok(Val::Bool(n1 >= n2)),
+            Op2::Equal => unreachable!(),
+        }
+    }
+
+    fn eval_if(
+        &mut self,
+        cond: &'a Expr,
+        thn: &'a Expr,
+        els: &'a Expr,
+        env: &HashMap<String, usize>,
+        frame: &mut Vec<Val>,
+    ) -> Result<Val, Exit> {
+        // Like the compiled code, any value other than false is true
+        if self.eval(cond, env, frame)? != Val::Bool(false) {
+            self.eval(thn, env, frame)
+        } else {
+            self.eval(els, env, frame)
+        }
+    }
+
+    fn eval_loop(
+        &mut self,
+        body: &'a Expr,
+        env: &HashMap<String, usize>,
+        frame: &mut Vec<Val>,
+        site: &Span,
+    ) -> Result<Val, Exit> {
+        loop {
+            self.step(site)?;
+            match self.eval(body, env, frame) {
+                Ok(_) => {}
+                Err(Exit::Break(v)) => return Ok(v),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn eval_set(
+        &mut self,
+        id: &str,
+        e: &'a Expr,
+        env: &HashMap<String, usize>,
+        frame: &mut Vec<Val>,
+    ) -> Result<Val, Exit> {
+        let v = self.eval(e, env, frame)?;
+        frame[env[id]] = v;
+        Ok(v)
+    }
+
+    fn eval_block(&mut self, es: &'a [Expr], env: &HashMap<String, usize>, frame: &mut Vec<Val>) -> Result<Val, Exit> {
+        let mut v = Val::Bool(false);
+        for e in es {
+            v = self.eval(e, env, frame)?;
+        }
+        Ok(v)
+    }
+
+    fn eval_print(&mut self, e: &'a Expr, env: &HashMap<String, usize>, frame: &mut Vec<Val>) -> Result<Val, Exit> {
+        let v = self.eval(e, env, frame)?;
+        self.print(v);
+        Ok(v)
+    }
+
+    fn eval_tup(&mut self, es: &'a [Expr], env: &HashMap<String, usize>, frame: &mut Vec<Val>) -> Result<Val, Exit> {
+        let mut elems = vec![];
+        for e in es {
+            elems.push(self.eval(e, env, frame)?);
+        }
+        self.heap.push(elems);
+        Ok(Val::Tup(self.heap.len() - 1))
+    }
+
+    // The index is evaluated before the tuple, like in the compiled code
+    fn eval_tup_get(
+        &mut self,
+        t: &'a Expr,
+        i: &'a Expr,
+        env: &HashMap<String, usize>,
+        frame: &mut Vec<Val>,
+        site: &Span,
+    ) -> Result<Val, Exit> {
+        let i = self.eval(i, env, frame)?;
+        let i = self.num(i, "tup-get", site)?;
+        let t = self.eval(t, env, frame)?;
+        let t = self.tup(t, "tup-get", site)?;
+        let i = self.index(t, i, site)?;
+        Ok(self.heap[t][i])
+    }
+
+    fn eval_tup_set(
+        &mut self,
+        t: &'a Expr,
+        i: &'a Expr,
+        e: &'a Expr,
+        env: &HashMap<String, usize>,
+        frame: &mut Vec<Val>,
+        site: &Span,
+    ) -> Result<Val, Exit> {
+        let i = self.eval(i, env, frame)?;
+        let i = self.num(i, "tup-set!", site)?;
+        let v = self.eval(e, env, frame)?;
+        let tv = self.eval(t, env, frame)?;
+        let t = self.tup(tv, "tup-set!", site)?;
+        let i = self.index(t, i, site)?;
+        self.heap[t][i] = v;
+        Ok(tv)
+    }
+
+    fn eval_tup_len(
+        &mut self,
+        t: &'a Expr,
+        env: &HashMap<String, usize>,
+        frame: &mut Vec<Val>,
+        site: &Span,
+    ) -> Result<Val, Exit> {
+        let t = self.eval(t, env, frame)?;
+        let t = self.tup(t, "tup-len", site)?;
+        Ok(Val::Num(self.heap[t].len() as i64))
+    }
+
+    // Calls the function of the program named fname
+    fn call(
+        &mut self,
+        fname: &str,
+        args: &'a [Expr],
+        env: &HashMap<String, usize>,
+        frame: &mut Vec<Val>,
+        site: &Span,
+    ) -> Result<Val, Exit> {
+        self.step(site)?;
+        let mut new_frame = vec![];
+        for arg in args {
+            new_frame.push(self.eval(arg, env, frame)?);
+        }
+        let def = self.funs[fname];
+        let new_env = def
+            .params
+            .iter()
+            .enumerate()
+            .map(|(i, id)| (id.clone(), i))
+            .collect();
+        match self.enter(&def.body, &new_env, &mut new_frame, &def.span) {
+            Err(Exit::Break(_)) => panic!("break outside of loop in {}", fname),
+            result => result,
+        }
+    }
+
+    // Evaluates the main expression of a program that passed the checker
+    pub fn eval_main(&mut self, main: &'a Expr) -> Result<Val, RuntimeError> {
+        match self.eval(main, &HashMap::new(), &mut vec![]) {
+            Ok(v) => Ok(v),
+            Err(Exit::Break(_)) => panic!("break outside of loop in main"),
+            Err(Exit::Error(e)) => Err(*e),
+        }
+    }
+}
+
+// Runs a program like the compiled executable does:
+// evaluates it, and prints its result after anything printed by the program
+pub fn run<W: Write>(p: &Program, input: Val, out: W) -> Result<(), RuntimeError> {
+    let mut interp = Interp::new(p, input, out);
+    let v = interp.eval_main(&p.main)?;
+    interp.print(v);
+    Ok(())
+}
@@ -0,0 +1,311 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::ffi::CStr;
+use std::io::Write;
+use std::os::raw::c_char;
+use std::{mem, ptr};
+
+use crate::asm::*;
+use crate::compiler::Asm;
+use crate::encoder::*;
+use crate::errors::SnekError;
+use crate::interp::{RuntimeError, Val};
+use crate::syntax::Span;
+
+#[path = "../runtime/gc.rs"]
+mod gc;
+#[path = "../runtime/print.rs"]
+mod print;
+
+use print::snek_str;
+
+// Words of heap given to a program, as many as the runtime gives
+const HEAP_WORDS: usize = 1_000_000;
+
+// Where snek_jit_entry was called from, so that an error can return there
+// from any depth of the compiled code
+#[repr(C)]
+#[derive(Default)]
+struct Context {
+    rsp: u64,
+    rbp: u64,
+    rbx: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    // Passed on to our_code_starts_here
+    stack_limit: u64,
+}
+
+// The callee-saved registers the compiled code uses, with their offsets in Context
+const SAVED: [(Reg, i64); 6] =
+    [(Reg::Rbp, 8), (Reg::Rbx, 16), (Reg::R12, 24), (Reg::R13, 32), (Reg::R14, 40), (Reg::R15, 48)];
+const STACK_LIMIT: i64 = 56;
+
+// The part of the stack left for the runtime functions when the compiled
+// code reports a stack overflow, as in the runtime
+const STACK_MARGIN: u64 = 64 << 10;
+
+// What the compiled code passes to snek_error
+#[derive(Clone, Copy)]
+struct Error {
+    code: i64,
+    site: i64,
+    value: i64,
+    extra: i64,
+}
+
+// What the in-process runtime functions need while the compiled code runs
+struct State {
+    context: *const Context,
+    out: *mut dyn Write,
+    error: Option<Error>,
+    heap_start: *mut u64,
+}
+
+thread_local! {
+    static STATE: RefCell<Option<State>> = const { RefCell::new(None) };
+}
+
+fn with_state<T>(f: impl FnOnce(&mut State) -> T) -> T {
+    STATE.with(|s| f(s.borrow_mut().as_mut().expect("no program is running")))
+}
+
+// Enters the compiled code, keeping where it was called from in a Context
+// Called with the input, the start and end of the heap, and the Context
+fn entry() -> Vec<Instr> {
+    let ctx = |disp| Arg::Mem(maddr_bd(Reg::Rcx, disp));
+    let mut instrs = vec![
+        Instr::Label("snek_jit_entry".to_string()),
+        Instr::Mov(ctx(0), Arg::Reg(Reg::Rsp)),
+    ];
+    for (r, disp) in SAVED {
+        instrs.push(Instr::Mov(ctx(disp), Arg::Reg(r)));
+    }
+    instrs.extend([
+        Instr::Mov(Arg::Reg(Reg::Rcx), ctx(STACK_LIMIT)),
+        // Aligns the call
+        Instr::Sub(Arg::Reg(Reg::Rsp), Arg::Imm(8)),
+        Instr::Call("our_code_starts_here".to_string()),
+        Instr::Add(Arg::Reg(Reg::Rsp), Arg::Imm(8)),
+        Instr::Ret,
+    ]);
+    instrs
+}
+
+// Takes the place of the runtime's snek_error: records the error, then
+// returns from snek_jit_entry with the registers it was called with
+fn error_exit() -> Vec<Instr> {
+    let ctx = |disp| Arg::Mem(maddr_bd(Reg::Rax, disp));
+    let mut instrs = vec![
+        Instr::Label("snek_error".to_string()),
+        // The error handler aligned rsp before calling here
+        Instr::Sub(Arg::Reg(Reg::Rsp), Arg::Imm(8)),
+        Instr::Call("snek_jit_error".to_string()),
+        Instr::Mov(Arg::Reg(Reg::Rsp), ctx(0)),
+    ];
+    for (r, disp) in SAVED {
+        instrs.push(Instr::Mov(Arg::Reg(r), ctx(disp)));
+    }
+    instrs.push(Instr::Ret);
+    instrs
+}
+
+// Records an error and returns the Context to go back to
+extern "C" fn snek_jit_error(code: i64, site: i64, value: i64, extra: i64) -> *const Context {
+    with_state(|s| {
+        s.error = Some(Error { code, site, value, extra });
+        s.context
+    })
+}
+
+// Collects garbage like the runtime's snek_gc
+unsafe extern "C" fn snek_gc(stack_top: *mut u64, stack_base: *mut u64, heap_ptr: *mut u64) -> *mut u64 {
+    gc::collect(with_state(|s| s.heap_start), heap_ptr, stack_top, stack_base)
+}
+
+extern "C" fn snek_print(val: i64) -> i64 {
+    let line = unsafe { snek_str(val, &mut HashSet::new()) };
+    // There is nowhere to report a failed write from here
+    with_state(|s| unsafe {
+        let _ = writeln!(&mut *s.out, "{}", line);
+    });
+    val
+}
+
+// The lowest address the compiled code may use on this thread's stack
+#[cfg(target_os = "linux")]
+fn stack_limit() -> u64 {
+    unsafe {
+        let mut attr = mem::zeroed();
+        let (mut addr, mut size) = (ptr::null_mut(), 0);
+        if libc::pthread_getattr_np(libc::pthread_self(), &mut attr) != 0
+            || libc::pthread_attr_getstack(&attr, &mut addr, &mut size) != 0
+        {
+            panic!("could not find the stack of this thread");
+        }
+        libc::pthread_attr_destroy(&mut attr);
+        addr as u64 + STACK_MARGIN
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn stack_limit() -> u64 {
+    unsafe {
+        let thread = libc::pthread_self();
+        let top = libc::pthread_get_stackaddr_np(thread) as u64;
+        top - libc::pthread_get_stacksize_np(thread) as u64 + STACK_MARGIN
+    }
+}
+
+// Memory mapped for the code, unmapped when dropped
+struct ExecBuffer {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl ExecBuffer {
+    // Maps bytes, then makes them executable and read-only
+    fn new(bytes: &[u8]) -> ExecBuffer {
+        let len = bytes.len().max(1);
+        unsafe {
+            let ptr = libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if ptr == libc::MAP_FAILED {
+                panic!("could not map memory for the code");
+            }
+            let ptr = ptr as *mut u8;
+            ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len());
+            if libc::mprotect(ptr as *mut libc::c_void, len, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                panic!("could not make the code executable");
+            }
+            ExecBuffer { ptr, len }
+        }
+    }
+}
+
+impl Drop for ExecBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+fn runtime_function(symbol: &str) -> u64 {
+    match symbol {
+        "snek_print" => snek_print as *const () as u64,
+        "snek_jit_error" => snek_jit_error as *const () as u64,
+        "snek_gc" => snek_gc as *const () as u64,
+        _ => panic!("Call to an unknown function: {}", symbol),
+    }
+}
+
+// Lays out the code, then a jump to each runtime function, then the data:
+// the runtime functions can be too far away for a call to reach
+// Returns the bytes with the code offsets and data offsets
+fn link(asm: &Asm) -> (Vec<u8>, Code, Code) {
+    let mut instrs = entry();
+    instrs.append(&mut error_exit());
+    instrs.extend(asm.text.iter().cloned());
+    let text = encode(&instrs);
+    let mut bytes = text.bytes.clone();
+    let mut stubs = vec![];
+    for reloc in &text.relocs {
+        let stub = match stubs.iter().find(|(s, _)| *s == reloc.symbol) {
+            Some((_, stub)) => *stub,
+            None => {
+                let stub = bytes.len();
+                // jmp [rip + 0], followed by the address
+                bytes.extend_from_slice(&[0xFF, 0x25, 0, 0, 0, 0]);
+                bytes.extend_from_slice(&runtime_function(&reloc.symbol).to_le_bytes());
+                stubs.push((reloc.symbol.clone(), stub));
+                stub
+            }
+        };
+        let rel = stub as i64 + reloc.addend - reloc.offset as i64;
+        bytes[reloc.offset..reloc.offset + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+    }
+    while bytes.len() % 8 != 0 {
+        bytes.push(0);
+    }
+    let mut data = encode_data(&asm.data, &text);
+    let data_start = bytes.len();
+    bytes.append(&mut data.bytes);
+    for offset in data.labels.values_mut() {
+        *offset += data_start;
+    }
+    (bytes, text, data)
+}
+
+fn repr(v: &Val) -> u64 {
+    match v {
+        Val::Num(n) => (*n as u64) << 1,
+        Val::Bool(true) => 7,
+        Val::Bool(false) => 3,
+        Val::Tup(_) => panic!("The input cannot be a tuple"),
+        Val::Fun(_) => panic!("The input cannot be a function"),
+    }
+}
+
+// The source position of a site id, read from the site table of the code
+unsafe fn site(source_file: *const c_char, site_table: *const u64, site: i64) -> Span {
+    let file = CStr::from_ptr(source_file).to_string_lossy();
+    let entry = site_table.add(2 * site as usize);
+    Span { file: file.as_ref().into(), line: entry.read() as usize, col: entry.add(1).read() as usize }
+}
+
+// The error snek_error would report, while the heap it refers to is still there
+unsafe fn runtime_error(error: Error, site: Span) -> RuntimeError {
+    let Some(snek_error) = SnekError::from_code(error.code) else {
+        panic!("Unknown error code: {}", error.code);
+    };
+    let show = |v: u64| snek_str(v as i64, &mut HashSet::new());
+    let operands = snek_error.operands(error.value as u64, error.extra as u64, show);
+    RuntimeError::Snek { error: snek_error, operands, site }
+}
+
+// Runs a compiled program in this process, the way the runtime would run
+// its executable: prints to out, and stops at the first error
+pub fn run<W: Write>(asm: &Asm, input: Val, mut out: W) -> Result<(), RuntimeError> {
+    let (bytes, text, data) = link(asm);
+    let buffer = ExecBuffer::new(&bytes);
+    let mut heap = vec![0u64; HEAP_WORDS];
+    let heap_start = heap.as_mut_ptr();
+    let heap_end = unsafe { heap_start.add(HEAP_WORDS) };
+    let mut context = Context { stack_limit: stack_limit(), ..Context::default() };
+    let context: *mut Context = &mut context;
+    let out: &mut dyn Write = &mut out;
+    let source_file = unsafe { buffer.ptr.add(data.labels["snek_source_file"]) } as *const c_char;
+    let site_table = unsafe { buffer.ptr.add(data.labels["snek_site_table"]) } as *const u64;
+    let state = State {
+        context,
+        // The pointer is only used until run returns, so its lifetime can be forgotten
+        out: unsafe { mem::transmute::<*mut dyn Write, *mut (dyn Write + 'static)>(out) },
+        error: None,
+        heap_start,
+    };
+    STATE.with(|s| *s.borrow_mut() = Some(state));
+
+    let result = unsafe {
+        let entry: extern "C" fn(u64, *mut u64, *mut u64, *mut Context) -> u64 =
+            mem::transmute(buffer.ptr.add(text.labels["snek_jit_entry"]));
+        entry(repr(&input), heap_start, heap_end, context)
+    };
+    let result = match with_state(|s| s.error) {
+        Some(error) => Err(unsafe { runtime_error(error, site(source_file, site_table, error.site)) }),
+        None => {
+            snek_print(result as i64);
+            Ok(())
+        }
+    };
+    STATE.with(|s| *s.borrow_mut() = None);
+    result
+}
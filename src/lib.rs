@@ -0,0 +1,18 @@
+pub mod syntax;
+pub mod asm;
+pub mod diagnostic;
+pub mod reader;
+pub mod parser;
+pub mod check;
+pub mod lift;
+pub mod compiler;
+pub mod encoder;
+pub mod elf;
+pub mod dwarf;
+pub mod jit;
+pub mod repl;
+pub mod interp;
+#[path = "../runtime/errors.rs"]
+pub mod errors;
+pub mod printer;
+pub mod driver;
@@ -0,0 +1,186 @@
+// Closure conversion and lambda lifting, which the compiler does first:
+// every lambda becomes a definition of the program, and its value a closure
+// of that definition with the values of the variables it uses
+//
+// A lifted lambda takes the tuple of its captured values after its
+// parameters, and binds them to the variables they come from before its
+// body. Calls of variables become calls of the function values they hold,
+// and functions of the program used as values become closures of nothing
+
+use im::HashSet;
+
+use crate::syntax::*;
+
+// The parameter of a lifted lambda that holds its captured values
+// It is not an identifier, so no variable can shadow it
+pub const ENV_PARAM: &str = "%env";
+
+// The number of arguments the function value of def is called with
+pub fn arity(def: &FunDef) -> usize {
+    match def.params.last() {
+        Some(param) if param == ENV_PARAM => def.params.len() - 1,
+        _ => def.params.len(),
+    }
+}
+
+fn add_free(id: &str, bound: &HashSet<String>, free: &mut Vec<String>) {
+    if id != "input" && !bound.contains(id) && !free.iter().any(|f| f == id) {
+        free.push(id.to_string());
+    }
+}
+
+// Adds the variables e uses that are not in bound to free, in the order
+// they first appear. e is already lifted, so it calls no variables
+fn free_vars(e: &Expr, bound: &HashSet<String>, free: &mut Vec<String>) {
+    match &e.kind {
+        ExprKind::Number(_) | ExprKind::Boolean(_) => {}
+        ExprKind::Var(id) => add_free(id, bound, free),
+        ExprKind::Set(id, e) => {
+            add_free(id, bound, free);
+            free_vars(e, bound, free);
+        }
+        ExprKind::Let(bindings, body) => {
+            let mut bound = bound.clone();
+            for (id, e) in bindings {
+                free_vars(e, &bound, free);
+                bound.insert(id.clone());
+            }
+            free_vars(body, &bound, free);
+        }
+        ExprKind::Lambda(params, body) => {
+            let mut bound = bound.clone();
+            bound.extend(params.iter().cloned());
+            free_vars(body, &bound, free);
+        }
+        ExprKind::UnOp(_, e)
+        | ExprKind::Loop(e)
+        | ExprKind::Break(e)
+        | ExprKind::Print(e)
+        | ExprKind::TupLen(e)
+        | ExprKind::Closure(_, e) => free_vars(e, bound, free),
+        ExprKind::BinOp(_, e1, e2) | ExprKind::TupGet(e1, e2) => {
+            free_vars(e1, bound, free);
+            free_vars(e2, bound, free);
+        }
+        ExprKind::If(e1, e2, e3) | ExprKind::TupSet(e1, e2, e3) => {
+            free_vars(e1, bound, free);
+            free_vars(e2, bound, free);
+            free_vars(e3, bound, free);
+        }
+        ExprKind::Block(es) | ExprKind::Tup(es) | ExprKind::Call(_, es) => {
+            for e in es {
+                free_vars(e, bound, free);
+            }
+        }
+        ExprKind::Apply(f, args) => {
+            free_vars(f, bound, free);
+            for arg in args {
+                free_vars(arg, bound, free);
+            }
+        }
+    }
+}
+
+struct Lifter {
+    // The names of the functions of the program
+    funs: HashSet<String>,
+    // The lifted lambdas, inner ones first
+    defs: Vec<FunDef>,
+}
+
+impl Lifter {
+    // scope holds the variables in scope, which calls by name go to before
+    // the functions of the program
+    fn lift(&mut self, e: &Expr, scope: &HashSet<String>) -> Expr {
+        let span = &e.span;
+        let mk = |kind| Expr::new(kind, span.clone());
+        if let ExprKind::Var(id) = &e.kind {
+            if !scope.contains(id) && self.funs.contains(id) {
+                return mk(ExprKind::Closure(id.clone(), Box::new(mk(ExprKind::Boolean(false)))));
+            }
+        }
+        let mut lift = |e: &Expr| Box::new(self.lift(e, scope));
+        let kind = match &e.kind {
+            ExprKind::Number(_) | ExprKind::Boolean(_) | ExprKind::Var(_) => e.kind.clone(),
+            ExprKind::Let(bindings, body) => {
+                let mut scope = scope.clone();
+                let mut lifted = vec![];
+                for (id, e) in bindings {
+                    lifted.push((id.clone(), self.lift(e, &scope)));
+                    scope.insert(id.clone());
+                }
+                ExprKind::Let(lifted, Box::new(self.lift(body, &scope)))
+            }
+            ExprKind::UnOp(op, e) => ExprKind::UnOp(op.clone(), lift(e)),
+            ExprKind::BinOp(op, e1, e2) => ExprKind::BinOp(op.clone(), lift(e1), lift(e2)),
+            ExprKind::If(cond, thn, els) => ExprKind::If(lift(cond), lift(thn), lift(els)),
+            ExprKind::Loop(e) => ExprKind::Loop(lift(e)),
+            ExprKind::Break(e) => ExprKind::Break(lift(e)),
+            ExprKind::Set(id, e) => ExprKind::Set(id.clone(), lift(e)),
+            ExprKind::Print(e) => ExprKind::Print(lift(e)),
+            ExprKind::TupGet(t, i) => ExprKind::TupGet(lift(t), lift(i)),
+            ExprKind::TupSet(t, i, e) => ExprKind::TupSet(lift(t), lift(i), lift(e)),
+            ExprKind::TupLen(t) => ExprKind::TupLen(lift(t)),
+            ExprKind::Closure(fname, env) => ExprKind::Closure(fname.clone(), lift(env)),
+            ExprKind::Block(es) => ExprKind::Block(es.iter().map(|e| self.lift(e, scope)).collect()),
+            ExprKind::Tup(es) => ExprKind::Tup(es.iter().map(|e| self.lift(e, scope)).collect()),
+            ExprKind::Call(fname, args) => {
+                let args = args.iter().map(|e| self.lift(e, scope)).collect();
+                if scope.contains(fname) {
+                    ExprKind::Apply(Box::new(mk(ExprKind::Var(fname.clone()))), args)
+                } else {
+                    ExprKind::Call(fname.clone(), args)
+                }
+            }
+            ExprKind::Apply(f, args) => {
+                let f = lift(f);
+                ExprKind::Apply(f, args.iter().map(|e| self.lift(e, scope)).collect())
+            }
+            ExprKind::Lambda(params, body) => {
+                let mut body_scope = scope.clone();
+                body_scope.extend(params.iter().cloned());
+                let body = self.lift(body, &body_scope);
+                let mut free = vec![];
+                free_vars(&body, &params.iter().cloned().collect(), &mut free);
+                let var = |id: &str| mk(ExprKind::Var(id.to_string()));
+
+                // Not an identifier, so it is not the name of any function
+                let name = format!("lambda.{}", self.defs.len());
+                let mut params = params.clone();
+                params.push(ENV_PARAM.to_string());
+                let (body, env) = if free.is_empty() {
+                    (body, ExprKind::Boolean(false))
+                } else {
+                    let bindings = free
+                        .iter()
+                        .enumerate()
+                        .map(|(i, id)| {
+                            let value = ExprKind::TupGet(Box::new(var(ENV_PARAM)), Box::new(mk(ExprKind::Number(i as i64))));
+                            (id.clone(), mk(value))
+                        })
+                        .collect();
+                    let env = ExprKind::Tup(free.iter().map(|id| var(id)).collect());
+                    (mk(ExprKind::Let(bindings, Box::new(body))), env)
+                };
+                self.defs.push(FunDef { name: name.clone(), params, body, span: span.clone() });
+                ExprKind::Closure(name, Box::new(mk(env)))
+            }
+        };
+        mk(kind)
+    }
+}
+
+// The program with every lambda lifted into a definition, after the
+// definitions it already has
+pub fn lift_lambdas(p: &Program) -> Program {
+    let funs = p.defs.iter().map(|def| def.name.clone()).collect();
+    let mut lifter = Lifter { funs, defs: vec![] };
+    let mut defs: Vec<FunDef> = p
+        .defs
+        .iter()
+        .map(|def| FunDef { body: lifter.lift(&def.body, &def.params.iter().cloned().collect()), ..def.clone() })
+        .collect();
+    let main = lifter.lift(&p.main, &HashSet::new());
+    defs.append(&mut lifter.defs);
+    Program { defs, main }
+}
@@ -1,30 +1,246 @@
 use std::env;
-use std::fs::File;
-use std::io::prelude::*;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
 
-pub mod syntax;
-pub mod asm;
-pub mod parser;
-pub mod compiler;
+use snek::diagnostic::Diagnostic;
+use snek::parser::*;
+use snek::check::*;
+use snek::compiler::*;
+use snek::driver;
+use snek::interp;
+use snek::jit;
+use snek::printer;
+use snek::repl;
+use snek::reader::comments;
+use snek::syntax::Program;
 
-use parser::*;
-use compiler::*;
+const USAGE: &str = "\
+Usage: snek <command> [options]
 
-fn main() -> std::io::Result<()> {
-    let args: Vec<String> = env::args().collect();
+Commands:
+  check <file>                 Parse and check a program
+  emit-ast <file>              Print the syntax tree of a program
+  emit-asm <file> [-o <out>]   Print the assembly for a program, or write it to out
+  build <file> [-o <out>]      Compile a program to an executable
+  run [--jit] <file> [-- <input>]
+                               Compile a program and run it on input, in memory with --jit
+  fmt [--check] <file>...      Format programs in place, or check that they are formatted
+  repl                         Enter definitions and expressions interactively
+  help                         Print this message";
 
-    let in_name = &args[1];
-    let out_name = &args[2];
+// A command line, once it has been checked
+enum Cmd {
+    Check { file: String },
+    EmitAst { file: String },
+    EmitAsm { file: String, out: Option<String> },
+    Build { file: String, out: Option<String> },
+    Run { file: String, input: Option<String>, jit: bool },
+    Fmt { files: Vec<String>, check: bool },
+    Repl,
+    Help,
+}
+
+// The arguments that follow a command
+#[derive(Default)]
+struct Args {
+    positional: Vec<String>,
+    out: Option<String>,
+    check: bool,
+    jit: bool,
+    // Everything after --
+    rest: Option<Vec<String>>,
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => match args.next() {
+                Some(out) if parsed.out.is_none() => parsed.out = Some(out.clone()),
+                Some(_) => return Err("-o given more than once".to_string()),
+                None => return Err("-o needs an output file".to_string()),
+            },
+            "--check" => parsed.check = true,
+            "--jit" => parsed.jit = true,
+            "--" => {
+                parsed.rest = Some(args.cloned().collect());
+                break;
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ => parsed.positional.push(arg.clone()),
+        }
+    }
+    Ok(parsed)
+}
 
-    let mut in_file = File::open(in_name)?;
-    let mut contents = String::new();
-    in_file.read_to_string(&mut contents)?;
+fn parse_cmd(args: &[String]) -> Result<Cmd, String> {
+    let (cmd, args) = match args.split_first() {
+        Some((cmd, args)) => (cmd.as_str(), parse_args(args)?),
+        None => return Err("no command given".to_string()),
+    };
+    // Options only make sense for some commands
+    let takes_out = matches!(cmd, "emit-asm" | "build");
+    if args.out.is_some() && !takes_out {
+        return Err(format!("{} does not take -o", cmd));
+    }
+    if args.check && cmd != "fmt" {
+        return Err(format!("{} does not take --check", cmd));
+    }
+    if args.jit && cmd != "run" {
+        return Err(format!("{} does not take --jit", cmd));
+    }
+    if args.rest.is_some() && cmd != "run" {
+        return Err(format!("{} does not take arguments after --", cmd));
+    }
+    let file = || match &args.positional[..] {
+        [file] => Ok(file.clone()),
+        [] => Err(format!("{} needs a file", cmd)),
+        _ => Err(format!("{} takes a single file", cmd)),
+    };
+    match cmd {
+        "check" => Ok(Cmd::Check { file: file()? }),
+        "emit-ast" => Ok(Cmd::EmitAst { file: file()? }),
+        "emit-asm" => Ok(Cmd::EmitAsm { file: file()?, out: args.out.clone() }),
+        "build" => Ok(Cmd::Build { file: file()?, out: args.out.clone() }),
+        "run" => {
+            let input = match args.rest.as_deref() {
+                None | Some([]) => None,
+                Some([input]) => Some(input.clone()),
+                Some(_) => return Err("run takes a single input".to_string()),
+            };
+            Ok(Cmd::Run { file: file()?, input, jit: args.jit })
+        }
+        "fmt" if args.positional.is_empty() => Err("fmt needs at least one file".to_string()),
+        "fmt" => Ok(Cmd::Fmt { files: args.positional, check: args.check }),
+        "repl" if !args.positional.is_empty() => Err("repl does not take files".to_string()),
+        "repl" => Ok(Cmd::Repl),
+        "help" | "-h" | "--help" => Ok(Cmd::Help),
+        _ => Err(format!("unknown command {}", cmd)),
+    }
+}
+
+// Prints every diagnostic and exits with an error
+fn report(diagnostics: Vec<Diagnostic>) -> ! {
+    for d in diagnostics {
+        eprintln!("{}", d);
+    }
+    process::exit(1);
+}
+
+// Prints an error that is not about the program and exits
+fn fail(message: String) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(1);
+}
 
-    let expr = parse(&contents);
-    let asm_program = compile(&expr);
+fn read(file: &str) -> String {
+    fs::read_to_string(file).unwrap_or_else(|e| fail(format!("could not read {}: {}", file, e)))
+}
+
+fn write(file: &str, contents: &str) {
+    fs::write(file, contents).unwrap_or_else(|e| fail(format!("could not write {}: {}", file, e)))
+}
+
+// Parses and checks a program, exiting with its diagnostics if there are any
+fn load(file: &str) -> Program {
+    let prog = match parse(file, &read(file)) {
+        Ok(prog) => prog,
+        Err(diagnostics) => report(diagnostics),
+    };
+    if let Err(diagnostics) = check(&prog) {
+        report(diagnostics);
+    }
+    prog
+}
+
+fn build(file: &str, out: &Path) {
+    driver::build(&compile_asm(&load(file)), out).unwrap_or_else(|e| fail(e));
+}
+
+// Rewrites each file in its canonical format. With check, only reports
+// the files that are not formatted, and fails if there are any
+fn fmt(files: &[String], check: bool) {
+    let mut unformatted = false;
+    for name in files {
+        let contents = read(name);
+        let prog = match parse(name, &contents) {
+            Ok(prog) => prog,
+            Err(diagnostics) => report(diagnostics),
+        };
+        let formatted = printer::format(&prog, &comments(name, &contents));
+        // Never write a file that would mean something else
+        match parse(name, &formatted) {
+            Ok(reparsed) if reparsed == prog => {}
+            _ => fail(format!("formatting {} would change its meaning", name)),
+        }
+        if formatted == contents {
+            continue;
+        }
+        if check {
+            eprintln!("{} is not formatted", name);
+            unformatted = true;
+        } else {
+            write(name, &formatted);
+        }
+    }
+    if unformatted {
+        process::exit(1);
+    }
+}
 
-    let mut out_file = File::create(out_name)?;
-    out_file.write_all(asm_program.as_bytes())?;
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let cmd = parse_cmd(&args).unwrap_or_else(|e| {
+        eprintln!("error: {}\n\n{}", e, USAGE);
+        process::exit(2);
+    });
 
-    Ok(())
+    match cmd {
+        Cmd::Check { file } => {
+            load(&file);
+        }
+        Cmd::EmitAst { file } => println!("{:#?}", load(&file)),
+        Cmd::EmitAsm { file, out } => {
+            let asm_program = compile(&load(&file));
+            match out {
+                Some(out) => write(&out, &asm_program),
+                None => print!("{}", asm_program),
+            }
+        }
+        Cmd::Build { file, out } => {
+            // By default, the executable is named after the program, in the current directory
+            let out = out.map(PathBuf::from).unwrap_or_else(|| {
+                PathBuf::from(Path::new(&file).file_stem().unwrap_or_default())
+            });
+            build(&file, &out);
+        }
+        Cmd::Run { file, input, jit: true } => {
+            let input = interp::parse_input(input.as_deref().unwrap_or("false")).unwrap_or_else(|e| fail(e));
+            let stdout = io::stdout();
+            if let Err(e) = jit::run(&compile_asm(&load(&file)), input, stdout.lock()) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        Cmd::Run { file, input, jit: false } => {
+            let dir = driver::TempDir::new().unwrap_or_else(|e| fail(e));
+            let exe = dir.path.join("program");
+            build(&file, &exe);
+            let mut program = Command::new(&exe);
+            program.args(input);
+            let status = program.status().unwrap_or_else(|e| fail(format!("could not run {}: {}", file, e)));
+            drop(dir);
+            process::exit(status.code().unwrap_or(1));
+        }
+        Cmd::Fmt { files, check } => fmt(&files, check),
+        Cmd::Repl => {
+            // Prompts are only for people typing
+            let prompt = unsafe { libc::isatty(0) } == 1;
+            repl::run(io::stdin().lock(), io::stdout(), prompt).unwrap_or_else(|e| fail(e.to_string()));
+        }
+        Cmd::Help => println!("{}", USAGE),
+    }
 }
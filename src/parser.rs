@@ -1,266 +1,441 @@
+use std::rc::Rc;
+
 use im::HashSet;
-use sexp::Atom::*;
-use sexp::*;
 
+use crate::diagnostic::*;
+use crate::reader::Atom::*;
+use crate::reader::*;
 use crate::syntax::*;
 
 const RESERVED_WORDS: &[&str] = &[
     "true", "false", "input", "let", "set!", "if", "block", "loop", "break", "print", "fun", "tup", "idx",
+    "lambda",
     // unary operators
-    "add1", "sub1", "isnum", "isbool", 
+    "add1", "sub1", "isnum", "isbool",
     // binary operators
     "+", "-", "*", "=", "<", "<=", ">", ">=",
 ];
 
-fn is_reserved_word(s: &String) -> bool {
-    RESERVED_WORDS.contains(&s.as_str())
+// Snek integers are 63 bits wide
+const MIN_INT: i64 = -(1 << 62);
+const MAX_INT: i64 = (1 << 62) - 1;
+
+fn is_reserved_word(s: &str) -> bool {
+    RESERVED_WORDS.contains(&s)
 }
 
-fn is_valid_id(id: &String) -> bool {
+fn is_valid_id(id: &str) -> bool {
     // A valid identifier starts with a lowercase letter
-    id.chars().nth(0).unwrap().is_lowercase()
+    id.chars().next().map_or(false, |c| c.is_lowercase())
     // contains only alphanumeric characters and underscores
     && id.chars().all(|c| c.is_alphanumeric() || c == '_')
     // and is not a reserved word
     && !is_reserved_word(id)
 }
 
-fn parse_binding(s: &Sexp) -> (String, Expr) {
+// The parser reports every problem it finds to diags and keeps going.
+// A None result means that the form had errors, which are already in diags.
+fn report<T>(diags: &mut Vec<Diagnostic>, kind: ErrorKind, message: String, span: Span) -> Option<T> {
+    diags.push(Diagnostic::new(kind, message, span));
+    None
+}
+
+fn invalid_id<T>(diags: &mut Vec<Diagnostic>, id: &str, span: &Span) -> Option<T> {
+    report(
+        diags,
+        ErrorKind::InvalidIdentifier,
+        format!("Invalid identifier or keyword: {}", id),
+        span.clone(),
+    )
+}
+
+fn parse_binding(s: &Sexp, diags: &mut Vec<Diagnostic>) -> Option<(String, Expr)> {
     match s {
-        Sexp::List(vec) => match &vec[..] {
-            [Sexp::Atom(S(id)), e] => {
-                if is_valid_id(id) {
-                    (id.to_string(), parse_expr(e))
-                } else {
-                    panic!("Invalid identifier or keyword: {}", id)
+        Sexp::List(vec, _) => match &vec[..] {
+            [Sexp::Atom(S(id), span), e] => {
+                let e = parse_expr(e, diags);
+                if !is_valid_id(id) {
+                    return invalid_id(diags, id, span);
                 }
+                Some((id.to_string(), e?))
             }
-            _ => panic!("Invalid binding: {:?}", s),
+            _ => report(diags, ErrorKind::InvalidBinding, format!("Invalid binding: {}", s), s.span()),
         },
-        _ => panic!("Invalid binding: {:?}", s),
+        _ => report(diags, ErrorKind::InvalidBinding, format!("Invalid binding: {}", s), s.span()),
     }
 }
 
-fn parse_bindings(s: &Sexp) -> Vec<(String, Expr)> {
+fn parse_bindings(s: &Sexp, diags: &mut Vec<Diagnostic>) -> Option<Vec<(String, Expr)>> {
     match s {
-        Sexp::List(vec) => {
-            if vec.len() == 0 {
-                panic!("Invalid bindings: {:?}", s);
+        Sexp::List(vec, _) => {
+            if vec.is_empty() {
+                return report(diags, ErrorKind::InvalidBinding, format!("Invalid bindings: {}", s), s.span());
             }
-            let bindings: Vec<(String, Expr)> = vec.iter().map(|s| parse_binding(s)).collect();
-            let vars: HashSet<String> = bindings.iter().map(|(id, _)| id).collect();
-            if vars.len() != bindings.len() {
-                panic!("Invalid bindings: Duplicate binding {:?}", s);
+            let mut bindings: Vec<Option<(String, Expr)>> = vec![];
+            let mut vars: HashSet<String> = HashSet::new();
+            for b in vec {
+                let binding = parse_binding(b, diags);
+                if let Some((id, _)) = &binding {
+                    if vars.contains(id) {
+                        report::<()>(
+                            diags,
+                            ErrorKind::DuplicateBinding,
+                            format!("Duplicate binding: {}", id),
+                            b.span(),
+                        );
+                    }
+                    vars.insert(id.clone());
+                }
+                bindings.push(binding);
             }
-            bindings
+            if vars.len() != vec.len() {
+                return None;
+            }
+            bindings.into_iter().collect()
         }
-        _ => panic!("Invalid bindings: {:?}", s),
+        _ => report(diags, ErrorKind::InvalidBinding, format!("Invalid bindings: {}", s), s.span()),
     }
 }
 
-fn parse_expr(s: &Sexp) -> Expr {
-    match s {
-        Sexp::Atom(I(i)) => Expr::Number(*i as i64),
-        Sexp::Atom(S(s)) if s == "true" => Expr::Boolean(true),
-        Sexp::Atom(S(s)) if s == "false" => Expr::Boolean(false),
-        // TODO: We want to panic if we see "input" in a function definition
-        Sexp::Atom(S(s)) if s == "input" => Expr::Var(s.to_string()),
-        Sexp::Atom(S(s)) => {
+fn parse_exprs(exprs: &[Sexp], diags: &mut Vec<Diagnostic>) -> Option<Vec<Expr>> {
+    // Parse every expression before giving up, so all their errors are reported
+    let parsed: Vec<Option<Expr>> = exprs.iter().map(|e| parse_expr(e, diags)).collect();
+    parsed.into_iter().collect()
+}
+
+fn parse_unop(op: Op1, e: &Sexp, diags: &mut Vec<Diagnostic>) -> Option<ExprKind> {
+    let e = parse_expr(e, diags);
+    Some(ExprKind::UnOp(op, Box::new(e?)))
+}
+
+fn parse_binop(op: Op2, e1: &Sexp, e2: &Sexp, diags: &mut Vec<Diagnostic>) -> Option<ExprKind> {
+    let e1 = parse_expr(e1, diags);
+    let e2 = parse_expr(e2, diags);
+    Some(ExprKind::BinOp(op, Box::new(e1?), Box::new(e2?)))
+}
+
+fn parse_expr(s: &Sexp, diags: &mut Vec<Diagnostic>) -> Option<Expr> {
+    let kind = match s {
+        Sexp::Atom(I(i), span) => {
+            if *i < MIN_INT || *i > MAX_INT {
+                return report(
+                    diags,
+                    ErrorKind::NumberBounds,
+                    format!("Invalid number: {} is out of range", i),
+                    span.clone(),
+                );
+            }
+            Some(ExprKind::Number(*i))
+        }
+        Sexp::Atom(S(s), _) if s == "true" => Some(ExprKind::Boolean(true)),
+        Sexp::Atom(S(s), _) if s == "false" => Some(ExprKind::Boolean(false)),
+        // input inside a function definition is rejected by the checker
+        Sexp::Atom(S(s), _) if s == "input" => Some(ExprKind::Var(s.to_string())),
+        Sexp::Atom(S(s), span) => {
             if is_valid_id(s) {
-                Expr::Var(s.to_string())
+                Some(ExprKind::Var(s.to_string()))
             } else {
-                panic!("Invalid identifier or keyword: {}", s)
+                invalid_id(diags, s, span)
             }
         }
-        Sexp::List(vec) => match &vec[..] {
-            [Sexp::Atom(S(op)), bindings, body] if op == "let" => {
-                Expr::Let(parse_bindings(bindings), Box::new(parse_expr(body)))
-            }
-            [Sexp::Atom(S(op)), e] if op == "add1" => {
-                Expr::UnOp(Op1::Add1, Box::new(parse_expr(e)))
+        Sexp::List(vec, _) => match &vec[..] {
+            [Sexp::Atom(S(op), _), bindings, body] if op == "let" => {
+                let bindings = parse_bindings(bindings, diags);
+                let body = parse_expr(body, diags);
+                Some(ExprKind::Let(bindings?, Box::new(body?)))
             }
-            [Sexp::Atom(S(op)), e] if op == "sub1" => {
-                Expr::UnOp(Op1::Sub1, Box::new(parse_expr(e)))
+            [Sexp::Atom(S(op), _), e] if op == "add1" => parse_unop(Op1::Add1, e, diags),
+            [Sexp::Atom(S(op), _), e] if op == "sub1" => parse_unop(Op1::Sub1, e, diags),
+            [Sexp::Atom(S(op), _), e] if op == "isnum" => parse_unop(Op1::IsNum, e, diags),
+            [Sexp::Atom(S(op), _), e] if op == "isbool" => parse_unop(Op1::IsBool, e, diags),
+            [Sexp::Atom(S(op), _), e1, e2] if op == "+" => parse_binop(Op2::Plus, e1, e2, diags),
+            [Sexp::Atom(S(op), _), e1, e2] if op == "-" => parse_binop(Op2::Minus, e1, e2, diags),
+            [Sexp::Atom(S(op), _), e1, e2] if op == "*" => parse_binop(Op2::Times, e1, e2, diags),
+            [Sexp::Atom(S(op), _), e1, e2] if op == "=" => parse_binop(Op2::Equal, e1, e2, diags),
+            [Sexp::Atom(S(op), _), e1, e2] if op == "<" => parse_binop(Op2::Less, e1, e2, diags),
+            [Sexp::Atom(S(op), _), e1, e2] if op == "<=" => parse_binop(Op2::LessEqual, e1, e2, diags),
+            [Sexp::Atom(S(op), _), e1, e2] if op == ">" => parse_binop(Op2::Greater, e1, e2, diags),
+            [Sexp::Atom(S(op), _), e1, e2] if op == ">=" => parse_binop(Op2::GreaterEqual, e1, e2, diags),
+            [Sexp::Atom(S(op), _), cond, thn, els] if op == "if" => {
+                let cond = parse_expr(cond, diags);
+                let thn = parse_expr(thn, diags);
+                let els = parse_expr(els, diags);
+                Some(ExprKind::If(Box::new(cond?), Box::new(thn?), Box::new(els?)))
             }
-            [Sexp::Atom(S(op)), e] if op == "isnum" => {
-                Expr::UnOp(Op1::IsNum, Box::new(parse_expr(e)))
+            [Sexp::Atom(S(op), _), body] if op == "loop" => {
+                let body = parse_expr(body, diags);
+                Some(ExprKind::Loop(Box::new(body?)))
             }
-            [Sexp::Atom(S(op)), e] if op == "isbool" => {
-                Expr::UnOp(Op1::IsBool, Box::new(parse_expr(e)))
+            [Sexp::Atom(S(op), _), e] if op == "break" => {
+                let e = parse_expr(e, diags);
+                Some(ExprKind::Break(Box::new(e?)))
             }
-            [Sexp::Atom(S(op)), e1, e2] if op == "+" => Expr::BinOp(
-                Op2::Plus,
-                Box::new(parse_expr(e1)),
-                Box::new(parse_expr(e2)),
-            ),
-            [Sexp::Atom(S(op)), e1, e2] if op == "-" => Expr::BinOp(
-                Op2::Minus,
-                Box::new(parse_expr(e1)),
-                Box::new(parse_expr(e2)),
-            ),
-            [Sexp::Atom(S(op)), e1, e2] if op == "*" => Expr::BinOp(
-                Op2::Times,
-                Box::new(parse_expr(e1)),
-                Box::new(parse_expr(e2)),
-            ),
-            [Sexp::Atom(S(op)), e1, e2] if op == "=" => Expr::BinOp(
-                Op2::Equal,
-                Box::new(parse_expr(e1)),
-                Box::new(parse_expr(e2)),
-            ),
-            [Sexp::Atom(S(op)), e1, e2] if op == "<" => Expr::BinOp(
-                Op2::Less,
-                Box::new(parse_expr(e1)),
-                Box::new(parse_expr(e2)),
-            ),
-            [Sexp::Atom(S(op)), e1, e2] if op == "<=" => Expr::BinOp(
-                Op2::LessEqual,
-                Box::new(parse_expr(e1)),
-                Box::new(parse_expr(e2)),
-            ),
-            [Sexp::Atom(S(op)), e1, e2] if op == ">" => Expr::BinOp(
-                Op2::Greater,
-                Box::new(parse_expr(e1)),
-                Box::new(parse_expr(e2)),
-            ),
-            [Sexp::Atom(S(op)), e1, e2] if op == ">=" => Expr::BinOp(
-                Op2::GreaterEqual,
-                Box::new(parse_expr(e1)),
-                Box::new(parse_expr(e2)),
-            ),
-            [Sexp::Atom(S(op)), cond, thn, els] if op == "if" => Expr::If(
-                Box::new(parse_expr(cond)),
-                Box::new(parse_expr(thn)),
-                Box::new(parse_expr(els)),
-            ),
-            [Sexp::Atom(S(op)), body] if op == "loop" => Expr::Loop(Box::new(parse_expr(body))),
-            [Sexp::Atom(S(op)), e] if op == "break" => Expr::Break(Box::new(parse_expr(e))),
-            [Sexp::Atom(S(op)), Sexp::Atom(S(id)), e] if op == "set!" => {
-                if is_valid_id(id) {
-                    Expr::Set(id.to_string(), Box::new(parse_expr(e)))
-                } else {
-                    panic!("Invalid identifier or keyword: {}", id)
+            [Sexp::Atom(S(op), _), Sexp::Atom(S(id), span), e] if op == "set!" => {
+                let e = parse_expr(e, diags);
+                if !is_valid_id(id) {
+                    return invalid_id(diags, id, span);
                 }
+                Some(ExprKind::Set(id.to_string(), Box::new(e?)))
             }
-            [Sexp::Atom(S(op)), exprs @ ..] if op == "block" => {
-                if exprs.len() == 0 {
-                    panic!("Invalid block: {:?}", s);
+            [Sexp::Atom(S(op), _), exprs @ ..] if op == "block" => {
+                if exprs.is_empty() {
+                    report(diags, ErrorKind::InvalidExpression, format!("Invalid block: {}", s), s.span())
                 } else {
-                    Expr::Block(exprs.into_iter().map(parse_expr).collect())
+                    Some(ExprKind::Block(parse_exprs(exprs, diags)?))
                 }
             }
-            [Sexp::Atom(S(op)), e] if op == "print" => {
-                Expr::Print(Box::new(parse_expr(e)))
+            [Sexp::Atom(S(op), _), e] if op == "print" => {
+                let e = parse_expr(e, diags);
+                Some(ExprKind::Print(Box::new(e?)))
             }
             // We allow tuples to have 0 elements
-            [Sexp::Atom(S(op)), exprs @ ..] if op == "tup" => {
-                Expr::Tup(exprs.into_iter().map(parse_expr).collect())
+            [Sexp::Atom(S(op), _), exprs @ ..] if op == "tup" => {
+                Some(ExprKind::Tup(parse_exprs(exprs, diags)?))
             }
-            [Sexp::Atom(S(op)), t, i] if op == "tup-get" => {
-                Expr::TupGet(Box::new(parse_expr(t)), Box::new(parse_expr(i)))
+            [Sexp::Atom(S(op), _), t, i] if op == "tup-get" => {
+                let t = parse_expr(t, diags);
+                let i = parse_expr(i, diags);
+                Some(ExprKind::TupGet(Box::new(t?), Box::new(i?)))
             }
-            [Sexp::Atom(S(op)), t, i, e] if op == "tup-set!" => {
-                Expr::TupSet(Box::new(parse_expr(t)), Box::new(parse_expr(i)), Box::new(parse_expr(e)))
+            [Sexp::Atom(S(op), _), t, i, e] if op == "tup-set!" => {
+                let t = parse_expr(t, diags);
+                let i = parse_expr(i, diags);
+                let e = parse_expr(e, diags);
+                Some(ExprKind::TupSet(Box::new(t?), Box::new(i?), Box::new(e?)))
             }
-            [Sexp::Atom(S(op)), t] if op == "tup-len" => {
-                Expr::TupLen(Box::new(parse_expr(t)))
+            [Sexp::Atom(S(op), _), t] if op == "tup-len" => {
+                let t = parse_expr(t, diags);
+                Some(ExprKind::TupLen(Box::new(t?)))
+            }
+            [Sexp::Atom(S(op), _), params, body] if op == "lambda" => {
+                let params = parse_params(params, diags);
+                let body = parse_expr(body, diags);
+                Some(ExprKind::Lambda(params?, Box::new(body?)))
+            }
+            // Any other form headed by a keyword has the wrong shape
+            // Its subexpressions are not checked, they may not be expressions at all
+            [Sexp::Atom(S(op), _), ..] if is_reserved_word(op) => {
+                report(diags, ErrorKind::InvalidExpression, format!("Invalid {} expression: {}", op, s), s.span())
             }
             // Function calls must be the last case since funname will capture anything
-            [Sexp::Atom(S(funname)), args @ ..] => {
-                if is_valid_id(funname) {
-                    Expr::Call(funname.to_string(), args.into_iter().map(parse_expr).collect())
-                } else {
-                    panic!("Invalid function name in call: {}", funname)
+            [Sexp::Atom(S(funname), span), args @ ..] => {
+                let args = parse_exprs(args, diags);
+                if !is_valid_id(funname) {
+                    return report(
+                        diags,
+                        ErrorKind::InvalidExpression,
+                        format!("Invalid function name in call: {}", funname),
+                        span.clone(),
+                    );
                 }
+                Some(ExprKind::Call(funname.to_string(), args?))
+            }
+            // Calls of anything else, like ((f) 3), call the function value it evaluates to
+            [f @ Sexp::List(..), args @ ..] => {
+                let f = parse_expr(f, diags);
+                let args = parse_exprs(args, diags);
+                Some(ExprKind::Apply(Box::new(f?), args?))
             }
-            _ => panic!("Invalid expression: {:?}", s),
+            _ => report(diags, ErrorKind::InvalidExpression, format!("Invalid expression: {}", s), s.span()),
         },
-        _ => panic!("Invalid expression: {:?}", s),
+    };
+    Some(Expr::new(kind?, s.span()))
+}
+
+// The parameters of a lambda: distinct identifiers
+fn parse_params(s: &Sexp, diags: &mut Vec<Diagnostic>) -> Option<Vec<String>> {
+    let vec = match s {
+        Sexp::List(vec, _) => vec,
+        _ => return report(diags, ErrorKind::InvalidExpression, format!("Invalid lambda parameters: {}", s), s.span()),
+    };
+    let mut params: Vec<String> = vec![];
+    let mut valid = true;
+    for param in vec {
+        match param {
+            Sexp::Atom(S(id), _) if is_valid_id(id) => params.push(id.to_string()),
+            Sexp::Atom(S(id), span) => {
+                valid = false;
+                invalid_id::<()>(diags, id, span);
+            }
+            _ => {
+                valid = false;
+                report::<()>(
+                    diags,
+                    ErrorKind::InvalidExpression,
+                    format!("All lambda parameters must be identifiers: {}", s),
+                    param.span(),
+                );
+            }
+        }
     }
+    if !valid {
+        return None;
+    }
+    let unique_params: HashSet<String> = params.iter().cloned().collect();
+    if params.len() != unique_params.len() {
+        return report(diags, ErrorKind::DuplicateParam, format!("Lambda parameters must be unique: {}", s), s.span());
+    }
+    Some(params)
 }
 
-fn parse_signature(s: &Sexp) -> (String, Vec<String>) {
+fn parse_signature(s: &Sexp, diags: &mut Vec<Diagnostic>) -> Option<(String, Vec<String>)> {
     match s {
-        Sexp::List(vec) => {
-            let idents: Vec<String> = vec.into_iter()
-            .map(|s| match s {
-                Sexp::Atom(S(s)) => {
-                    if is_valid_id(s) {
-                        s.to_string()
-                    } else {
-                        panic!("Invalid identifier or keyword: {}", s)
+        Sexp::List(vec, _) => {
+            let mut idents: Vec<String> = vec![];
+            let mut valid = true;
+            for ident in vec {
+                match ident {
+                    Sexp::Atom(S(id), span) => {
+                        if is_valid_id(id) {
+                            idents.push(id.to_string());
+                        } else {
+                            valid = false;
+                            invalid_id::<()>(diags, id, span);
+                        }
+                    }
+                    _ => {
+                        valid = false;
+                        report::<()>(
+                            diags,
+                            ErrorKind::InvalidFunDef,
+                            format!("All elements in function signature must be identifiers: {}", s),
+                            ident.span(),
+                        );
                     }
                 }
-                _ => panic!("All elements in function signature must be identifiers: {:?}", s),
-            }).collect();
-            if idents.is_empty() { panic!("Function must have a name: {:?}", s) }
+            }
+            if vec.is_empty() {
+                return report(diags, ErrorKind::InvalidFunDef, format!("Function must have a name: {}", s), s.span());
+            }
+            if !valid {
+                return None;
+            }
             let name = idents[0].clone();
             let params = idents[1..].to_vec();
             let unique_params: HashSet<String> = params.iter().cloned().collect();
             if params.len() != unique_params.len() {
-                panic!("Function parameters must be unique: {:?}", s)
+                return report(
+                    diags,
+                    ErrorKind::DuplicateParam,
+                    format!("Function parameters must be unique: {}", s),
+                    s.span(),
+                );
             }
-            (name, params)
+            Some((name, params))
         },
-        _ => panic!("Function signature must be a list: {:?}", s),
+        _ => report(diags, ErrorKind::InvalidFunDef, format!("Function signature must be a list: {}", s), s.span()),
     }
 }
 
 fn is_fundef(s: &Sexp) -> bool {
     match s {
-        Sexp::List(vec) => match &vec[..] {
-            [Sexp::Atom(S(op)), Sexp::List(_), _] if op == "fun" => true,
-            _ => false,
-        },
+        Sexp::List(vec, _) => matches!(&vec[..], [Sexp::Atom(S(op), _), Sexp::List(..), _] if op == "fun"),
         _ => false,
     }
 }
 
-fn parse_fundef(s: &Sexp, fun_env: &HashSet<String>) -> FunDef {
+fn parse_fundef(s: &Sexp, diags: &mut Vec<Diagnostic>) -> Option<FunDef> {
     match s {
-        Sexp::List(vec) => match &vec[..] {
-            [Sexp::Atom(S(op)), signature, body] if op == "fun" => {
-                let (name, params) = parse_signature(signature);
-                if fun_env.contains(&name) {
-                    panic!("Function name must be unique: {:?}", s)
-                }
-                let body_expr = parse_expr(body);
-                FunDef { name, params, body: body_expr }
+        Sexp::List(vec, _) => match &vec[..] {
+            [Sexp::Atom(S(op), _), signature, body] if op == "fun" => {
+                let signature = parse_signature(signature, diags);
+                let body = parse_expr(body, diags);
+                let (name, params) = signature?;
+                Some(FunDef { name, params, body: body?, span: s.span() })
             },
-            _ => panic!("Invalid function definition format: {:?}", s),
+            _ => report(diags, ErrorKind::InvalidFunDef, format!("Invalid function definition format: {}", s), s.span()),
         },
-        _ => panic!("Function definition must be a list: {:?}", s),
+        _ => report(diags, ErrorKind::InvalidFunDef, format!("Function definition must be a list: {}", s), s.span()),
     }
 }
 
-fn parse_program(s: &Sexp) -> Program {
-    match s {
-        Sexp::List(vec) => {
-            let mut defs: Vec<FunDef> = vec![];
-            let mut fun_env: HashSet<String> = HashSet::new();
-            for def_or_exp in vec {
-                if is_fundef(def_or_exp) {
-                    let def = parse_fundef(def_or_exp, &fun_env);
-                    fun_env.insert(def.name.clone());
-                    defs.push(def);
+fn parse_program(file: &str, sexps: &[Sexp], diags: &mut Vec<Diagnostic>) -> Option<Program> {
+    let mut defs: Vec<FunDef> = vec![];
+    let mut fun_env: HashSet<String> = HashSet::new();
+    for (i, def_or_exp) in sexps.iter().enumerate() {
+        if is_fundef(def_or_exp) {
+            if let Some(def) = parse_fundef(def_or_exp, diags) {
+                if fun_env.contains(&def.name) {
+                    report::<()>(
+                        diags,
+                        ErrorKind::DuplicateFunction,
+                        format!("Function name must be unique: {}", def.name),
+                        def.span.clone(),
+                    );
+                }
+                fun_env.insert(def.name.clone());
+                defs.push(def);
+            }
+        } else {
+            let main = parse_expr(def_or_exp, diags);
+            // The main expression ends the program, so anything after it is
+            // reported, along with the problems inside it
+            for extra in &sexps[i + 1..] {
+                if is_fundef(extra) {
+                    parse_fundef(extra, diags);
                 } else {
-                    // if there are more than one expression in the program,
-                    // only the first one is the main expression
-                    return Program { defs, main: parse_expr(def_or_exp) }
+                    parse_expr(extra, diags);
                 }
-            };
-            panic!("Only definitions found in program: {:?}", s);
-        },
-        _ => panic!("Program must be a list: {:?}", s),
+                report::<()>(
+                    diags,
+                    ErrorKind::ExtraForm,
+                    "Invalid program: unexpected form after the main expression".to_string(),
+                    extra.span(),
+                );
+            }
+            return Some(Program { defs, main: main? });
+        }
+    };
+    report(
+        diags,
+        ErrorKind::MissingMain,
+        "Invalid program: no main expression found".to_string(),
+        sexps.last().map_or(Span { file: Rc::from(file), line: 1, col: 1 }, |s| s.span()),
+    )
+}
+
+// Parses s, the contents of file
+// On failure, returns every problem found, ordered by source position
+pub fn parse(file: &str, s: &str) -> Result<Program, Vec<Diagnostic>> {
+    let mut diags = vec![];
+    let sexps = read(file, s, &mut diags);
+    let program = parse_program(file, &sexps, &mut diags);
+    match program {
+        Some(program) if diags.is_empty() => Ok(program),
+        _ => {
+            diags.sort_by(|d1, d2| d1.span.cmp(&d2.span));
+            Err(diags)
+        }
     }
 }
 
-pub fn parse(s: &str) -> Program {
-    // wrap s in parens to make it a valid S-expression
-    let input_prog = format!("({})", s);
-    let sexp_parse = match sexp::parse(&input_prog) {
-        Ok(sexp) => sexp,
-        Err(e) => panic!("Invalid expression: {}", e),
+// A single definition or expression, as entered at the REPL
+pub enum Entry {
+    Def(FunDef),
+    Expr(Expr),
+}
+
+// Parses s as exactly one definition or expression
+pub fn parse_entry(file: &str, s: &str) -> Result<Entry, Vec<Diagnostic>> {
+    let mut diags = vec![];
+    let sexps = read(file, s, &mut diags);
+    let entry = match &sexps[..] {
+        [s] if is_fundef(s) => parse_fundef(s, &mut diags).map(Entry::Def),
+        [s] => parse_expr(s, &mut diags).map(Entry::Expr),
+        [] => {
+            let message = "Expected a definition or an expression".to_string();
+            report(&mut diags, ErrorKind::Syntax, message, Span { file: Rc::from(file), line: 1, col: 1 })
+        }
+        [_, extra, ..] => {
+            let message = "Expected a single definition or expression".to_string();
+            report(&mut diags, ErrorKind::Syntax, message, extra.span())
+        }
     };
-    parse_program(&sexp_parse)
+    match entry {
+        Some(entry) if diags.is_empty() => Ok(entry),
+        _ => {
+            diags.sort_by(|d1, d2| d1.span.cmp(&d2.span));
+            Err(diags)
+        }
+    }
 }
@@ -0,0 +1,272 @@
+use crate::reader::*;
+use crate::syntax::*;
+
+// Lines longer than this are broken up
+const WIDTH: usize = 80;
+
+fn atom(s: &str) -> Sexp {
+    Sexp::Atom(Atom::S(s.to_string()), Span::default())
+}
+
+fn list(vec: Vec<Sexp>, span: &Span) -> Sexp {
+    Sexp::List(vec, span.clone())
+}
+
+pub fn unop_name(op: &Op1) -> &'static str {
+    match op {
+        Op1::Add1 => "add1",
+        Op1::Sub1 => "sub1",
+        Op1::IsNum => "isnum",
+        Op1::IsBool => "isbool",
+    }
+}
+
+pub fn binop_name(op: &Op2) -> &'static str {
+    match op {
+        Op2::Plus => "+",
+        Op2::Minus => "-",
+        Op2::Times => "*",
+        Op2::Equal => "=",
+        Op2::Less => "<",
+        Op2::LessEqual => "<=",
+        Op2::Greater => ">",
+        Op2::GreaterEqual => ">=",
+    }
+}
+
+// Converts an expression back to the s-expression it is parsed from
+// Lists and atoms keep the position of the expression they come from,
+// so that comments can be put back in place
+pub fn expr_to_sexp(e: &Expr) -> Sexp {
+    let span = &e.span;
+    let form = |op: &str, es: &[&Expr]| {
+        let mut vec = vec![atom(op)];
+        vec.extend(es.iter().map(|e| expr_to_sexp(e)));
+        list(vec, span)
+    };
+    match &e.kind {
+        ExprKind::Number(n) => Sexp::Atom(Atom::I(*n), span.clone()),
+        ExprKind::Boolean(b) => Sexp::Atom(Atom::S(b.to_string()), span.clone()),
+        ExprKind::Var(id) => Sexp::Atom(Atom::S(id.clone()), span.clone()),
+        ExprKind::Let(bindings, body) => {
+            // Bindings have no position of their own, so they take the one of their value
+            let bindings: Vec<Sexp> = bindings
+                .iter()
+                .map(|(id, e)| list(vec![atom(id), expr_to_sexp(e)], &e.span))
+                .collect();
+            let bindings_span = bindings.first().map_or(Span::default(), |b| b.span());
+            list(vec![atom("let"), list(bindings, &bindings_span), expr_to_sexp(body)], span)
+        }
+        ExprKind::UnOp(op, e) => form(unop_name(op), &[e]),
+        ExprKind::BinOp(op, e1, e2) => form(binop_name(op), &[e1, e2]),
+        ExprKind::If(cond, thn, els) => form("if", &[cond, thn, els]),
+        ExprKind::Loop(e) => form("loop", &[e]),
+        ExprKind::Break(e) => form("break", &[e]),
+        ExprKind::Set(id, e) => list(vec![atom("set!"), atom(id), expr_to_sexp(e)], span),
+        ExprKind::Block(es) => form("block", &es.iter().collect::<Vec<_>>()),
+        ExprKind::Print(e) => form("print", &[e]),
+        ExprKind::Tup(es) => form("tup", &es.iter().collect::<Vec<_>>()),
+        ExprKind::TupGet(t, i) => form("tup-get", &[t, i]),
+        ExprKind::TupSet(t, i, e) => form("tup-set!", &[t, i, e]),
+        ExprKind::TupLen(t) => form("tup-len", &[t]),
+        ExprKind::Call(fname, args) => form(fname, &args.iter().collect::<Vec<_>>()),
+        ExprKind::Lambda(params, body) => {
+            let params = list(params.iter().map(|p| atom(p)).collect(), &Span::default());
+            list(vec![atom("lambda"), params, expr_to_sexp(body)], span)
+        }
+        ExprKind::Apply(f, args) => {
+            let mut vec = vec![expr_to_sexp(f)];
+            vec.extend(args.iter().map(expr_to_sexp));
+            list(vec, span)
+        }
+        ExprKind::Closure(fname, env) => list(vec![atom("closure"), atom(fname), expr_to_sexp(env)], span),
+    }
+}
+
+pub fn fundef_to_sexp(def: &FunDef) -> Sexp {
+    let mut signature = vec![atom(&def.name)];
+    signature.extend(def.params.iter().map(|p| atom(p)));
+    let signature = list(signature, &Span::default());
+    list(vec![atom("fun"), signature, expr_to_sexp(&def.body)], &def.span)
+}
+
+// The position of the last thing in s that has one
+fn last_span(s: &Sexp) -> Span {
+    match s {
+        Sexp::Atom(_, span) => span.clone(),
+        Sexp::List(vec, span) => vec.iter().map(last_span).fold(span.clone(), Span::max),
+    }
+}
+
+// How the arguments of a form are laid out when it does not fit in the line
+enum Layout {
+    // (op arg1
+    //     arg2)
+    Aligned,
+    // (op arg1
+    //   body)
+    Header,
+    // (op
+    //   body1
+    //   body2)
+    Body,
+}
+
+fn layout(head: &str) -> Layout {
+    match head {
+        "let" | "fun" | "lambda" => Layout::Header,
+        "block" | "loop" => Layout::Body,
+        _ => Layout::Aligned,
+    }
+}
+
+// Lays out s-expressions, putting back the comments of the source they come from
+struct Printer {
+    out: String,
+    // Comments not printed yet, the next one last
+    comments: Vec<Comment>,
+}
+
+impl Printer {
+    fn new(comments: &[Comment]) -> Printer {
+        let mut comments = comments.to_vec();
+        comments.reverse();
+        Printer { out: String::new(), comments }
+    }
+
+    fn newline(&mut self, indent: usize) {
+        // No trailing whitespace on empty lines
+        let trimmed = self.out.trim_end_matches(' ').len();
+        self.out.truncate(trimmed);
+        self.out.push('\n');
+        self.out.push_str(&" ".repeat(indent));
+    }
+
+    fn at_line_start(&self) -> bool {
+        self.out.rsplit('\n').next().unwrap().trim().is_empty()
+    }
+
+    fn has_comments_before(&self, span: &Span) -> bool {
+        self.comments.last().map_or(false, |c| c.span < *span)
+    }
+
+    // Prints the comments that come before span in the source, leaving the
+    // cursor at column indent of a new line if there were any
+    fn comments_before(&mut self, span: &Span, indent: usize) {
+        while self.has_comments_before(span) {
+            let comment = self.comments.pop().unwrap();
+            if !comment.own_line && self.at_line_start() && !self.out.trim().is_empty() {
+                // A comment after some code goes back to the end of the line of that code
+                let end = self.out.trim_end().len();
+                let rest = self.out.split_off(end);
+                self.out.push(' ');
+                self.out.push_str(&comment.text);
+                self.out.push_str(&rest);
+                continue;
+            }
+            if !self.at_line_start() {
+                if comment.own_line {
+                    self.newline(indent);
+                } else {
+                    self.out.push(' ');
+                }
+            }
+            self.out.push_str(&comment.text);
+            self.newline(indent);
+        }
+    }
+
+    // Prints s, assuming the cursor is at column indent
+    // Lists that fit in the line and contain no comments are written in one line,
+    // the rest are broken up
+    fn render(&mut self, s: &Sexp, indent: usize) {
+        self.comments_before(&s.span(), indent);
+        let flat = s.to_string();
+        let vec = match s {
+            Sexp::List(vec, _)
+                if !vec.is_empty()
+                    && (indent + flat.len() > WIDTH || self.has_comments_before(&last_span(s))) =>
+            {
+                vec
+            }
+            _ => {
+                self.out.push_str(&flat);
+                return;
+            }
+        };
+        self.out.push('(');
+        match &vec[0] {
+            Sexp::Atom(Atom::S(head), _) => {
+                self.out.push_str(head);
+                let first_col = indent + head.len() + 2;
+                let rest_col = match layout(head) {
+                    Layout::Aligned => first_col,
+                    Layout::Header | Layout::Body => indent + 2,
+                };
+                for (i, arg) in vec[1..].iter().enumerate() {
+                    if i == 0 && !matches!(layout(head), Layout::Body) {
+                        self.out.push(' ');
+                        self.render(arg, first_col);
+                    } else {
+                        self.newline(rest_col);
+                        self.render(arg, rest_col);
+                    }
+                }
+            }
+            // Lists of lists, like let bindings, are aligned one column in
+            _ => {
+                for (i, elem) in vec.iter().enumerate() {
+                    if i > 0 {
+                        self.newline(indent + 1);
+                    }
+                    self.render(elem, indent + 1);
+                }
+            }
+        }
+        self.out.push(')');
+    }
+
+    // Prints the comments after the last expression
+    fn finish(mut self) -> String {
+        while let Some(comment) = self.comments.pop() {
+            if comment.own_line {
+                self.newline(0);
+            } else {
+                self.out.push(' ');
+            }
+            self.out.push_str(&comment.text);
+        }
+        self.out.push('\n');
+        self.out
+    }
+}
+
+pub fn expr_to_string(e: &Expr) -> String {
+    let mut printer = Printer::new(&[]);
+    printer.render(&expr_to_sexp(e), 0);
+    printer.out
+}
+
+pub fn fundef_to_string(def: &FunDef) -> String {
+    let mut printer = Printer::new(&[]);
+    printer.render(&fundef_to_sexp(def), 0);
+    printer.out
+}
+
+// Prints a program in its canonical form, along with the comments of its source
+// Definitions are separated by blank lines, and the main expression comes last.
+// The result parses back to the same program
+pub fn format(p: &Program, comments: &[Comment]) -> String {
+    let mut printer = Printer::new(comments);
+    for def in &p.defs {
+        printer.render(&fundef_to_sexp(def), 0);
+        printer.newline(0);
+        printer.newline(0);
+    }
+    printer.render(&expr_to_sexp(&p.main), 0);
+    printer.finish()
+}
+
+pub fn program_to_string(p: &Program) -> String {
+    format(p, &[])
+}
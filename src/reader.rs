@@ -0,0 +1,184 @@
+use std::fmt;
+use std::rc::Rc;
+
+use crate::diagnostic::*;
+use crate::syntax::Span;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Atom {
+    // symbols, keywords and operators
+    S(String),
+    // integer literals that fit in an i64
+    I(i64),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+// An s-expression annotated with the position where it starts
+pub enum Sexp {
+    Atom(Atom, Span),
+    List(Vec<Sexp>, Span),
+}
+
+impl Sexp {
+    pub fn span(&self) -> Span {
+        match self {
+            Sexp::Atom(_, span) | Sexp::List(_, span) => span.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Sexp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sexp::Atom(Atom::S(s), _) => write!(f, "{}", s),
+            Sexp::Atom(Atom::I(i), _) => write!(f, "{}", i),
+            Sexp::List(vec, _) => {
+                write!(f, "(")?;
+                for (i, s) in vec.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", s)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+// A comment, from its ';' to the end of the line
+pub struct Comment {
+    pub text: String,
+    pub span: Span,
+    // Whether the comment is alone in its line, rather than after some code
+    pub own_line: bool,
+}
+
+// Collects the comments in s, the contents of file, in order
+pub fn comments(file: &str, s: &str) -> Vec<Comment> {
+    let file: Rc<str> = Rc::from(file);
+    let mut comments = vec![];
+    for (i, line) in s.lines().enumerate() {
+        if let Some(start) = line.find(';') {
+            let code = &line[..start];
+            comments.push(Comment {
+                text: line[start..].trim_end().to_string(),
+                span: Span { file: file.clone(), line: i + 1, col: code.chars().count() + 1 },
+                own_line: code.trim().is_empty(),
+            });
+        }
+    }
+    comments
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || c == '(' || c == ')' || c == ';'
+}
+
+fn is_integer_literal(s: &str) -> bool {
+    let digits = s.strip_prefix('-').unwrap_or(s);
+    !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+fn read_atom(token: String, span: Span, diags: &mut Vec<Diagnostic>) -> Sexp {
+    if let Ok(i) = token.parse::<i64>() {
+        Sexp::Atom(Atom::I(i), span)
+    } else if is_integer_literal(&token) {
+        diags.push(Diagnostic::new(
+            ErrorKind::NumberBounds,
+            format!("Invalid number: {} is out of range", token),
+            span.clone(),
+        ));
+        // Keep a placeholder so that the enclosing form can still be checked
+        Sexp::Atom(Atom::I(0), span)
+    } else {
+        Sexp::Atom(Atom::S(token), span)
+    }
+}
+
+// Reads all the top-level s-expressions in s, the contents of file
+// Comments start with ';' and run until the end of the line
+// Unbalanced parentheses are reported to diags, and reading continues
+// as if the stray ')' was not there or the missing ')' were at the end
+pub fn read(file: &str, s: &str, diags: &mut Vec<Diagnostic>) -> Vec<Sexp> {
+    let file: Rc<str> = Rc::from(file);
+    let mut chars = s.chars().peekable();
+    let mut pos = Span { file, line: 1, col: 1 };
+    // Each open list keeps its elements and the position of its '('
+    let mut open: Vec<(Vec<Sexp>, Span)> = vec![];
+    let mut top: Vec<Sexp> = vec![];
+
+    while let Some(&c) = chars.peek() {
+        let start = pos.clone();
+        let sexp = if c == '\n' {
+            chars.next();
+            pos.line += 1;
+            pos.col = 1;
+            None
+        } else if c.is_whitespace() {
+            chars.next();
+            pos.col += 1;
+            None
+        } else if c == ';' {
+            while let Some(&c) = chars.peek() {
+                if c == '\n' {
+                    break;
+                }
+                chars.next();
+                pos.col += 1;
+            }
+            None
+        } else if c == '(' {
+            chars.next();
+            pos.col += 1;
+            open.push((vec![], start));
+            None
+        } else if c == ')' {
+            chars.next();
+            pos.col += 1;
+            match open.pop() {
+                Some((vec, span)) => Some(Sexp::List(vec, span)),
+                None => {
+                    diags.push(Diagnostic::new(
+                        ErrorKind::Syntax,
+                        "Invalid expression: unexpected ')'".to_string(),
+                        start,
+                    ));
+                    None
+                }
+            }
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if is_delimiter(c) {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+                pos.col += 1;
+            }
+            Some(read_atom(token, start, diags))
+        };
+        if let Some(sexp) = sexp {
+            match open.last_mut() {
+                Some((vec, _)) => vec.push(sexp),
+                None => top.push(sexp),
+            }
+        }
+    }
+
+    while let Some((vec, span)) = open.pop() {
+        diags.push(Diagnostic::new(
+            ErrorKind::Syntax,
+            "Invalid expression: unclosed '('".to_string(),
+            span.clone(),
+        ));
+        let sexp = Sexp::List(vec, span);
+        match open.last_mut() {
+            Some((vec, _)) => vec.push(sexp),
+            None => top.push(sexp),
+        }
+    }
+    top
+}
@@ -0,0 +1,167 @@
+use std::io::{self, BufRead, Write};
+
+use crate::asm::*;
+use crate::check::check;
+use crate::compiler::compile_asm;
+use crate::diagnostic::Diagnostic;
+use crate::interp::Val;
+use crate::jit;
+use crate::parser::*;
+use crate::printer::fundef_to_string;
+use crate::syntax::*;
+
+// Entries are reported as coming from this file
+const FILE: &str = "<repl>";
+
+const HELP: &str = "\
+Enter a definition to add it to the session, or an expression to run it.
+Commands:
+  :asm <expr>   Print the code compiled for an expression
+  :ast <expr>   Print the syntax tree of a definition or expression
+  :defs         Print the definitions of the session
+  :help         Print this message
+  :quit         Leave the REPL";
+
+// A REPL session: the definitions entered so far
+#[derive(Default)]
+pub struct Repl {
+    defs: Vec<FunDef>,
+}
+
+fn report<W: Write>(out: &mut W, diagnostics: Vec<Diagnostic>) -> io::Result<()> {
+    for d in diagnostics {
+        writeln!(out, "{}", d)?;
+    }
+    Ok(())
+}
+
+// Whether s has no more '(' than ')', outside of comments
+fn is_complete(s: &str) -> bool {
+    let mut depth = 0;
+    for line in s.lines() {
+        for c in line.chars().take_while(|c| *c != ';') {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => {}
+            }
+        }
+    }
+    depth <= 0
+}
+
+impl Repl {
+    pub fn new() -> Repl {
+        Repl::default()
+    }
+
+    // The session's definitions, with def in place of the one with its name
+    fn with_def(&self, def: FunDef) -> Vec<FunDef> {
+        let mut defs = self.defs.clone();
+        match defs.iter_mut().find(|d| d.name == def.name) {
+            Some(old) => *old = def,
+            None => defs.push(def),
+        }
+        defs
+    }
+
+    // The program that runs main after the session's definitions
+    fn program(&self, main: Expr) -> Result<Program, Vec<Diagnostic>> {
+        let p = Program { defs: self.defs.clone(), main };
+        check(&p)?;
+        Ok(p)
+    }
+
+    fn define<W: Write>(&mut self, def: FunDef, out: &mut W) -> io::Result<()> {
+        let name = def.name.clone();
+        // The definition must fit with the others, so they are checked again
+        let main = Expr { kind: ExprKind::Number(0), span: def.span.clone() };
+        let p = Program { defs: self.with_def(def), main };
+        match check(&p) {
+            Err(diagnostics) => report(out, diagnostics),
+            Ok(()) => {
+                self.defs = p.defs;
+                writeln!(out, "defined {}", name)
+            }
+        }
+    }
+
+    fn run<W: Write>(&self, main: Expr, out: &mut W) -> io::Result<()> {
+        let p = match self.program(main) {
+            Ok(p) => p,
+            Err(diagnostics) => return report(out, diagnostics),
+        };
+        match jit::run(&compile_asm(&p), Val::Bool(false), &mut *out) {
+            Ok(()) => Ok(()),
+            Err(e) => writeln!(out, "{}", e),
+        }
+    }
+
+    // Prints the code of main, without the definitions it comes after
+    fn asm<W: Write>(&self, main: Expr, out: &mut W) -> io::Result<()> {
+        let p = match self.program(main) {
+            Ok(p) => p,
+            Err(diagnostics) => return report(out, diagnostics),
+        };
+        let text = compile_asm(&p).text;
+        let start = text.iter().position(|i| *i == Instr::Label("our_code_starts_here".to_string())).unwrap();
+        writeln!(out, "{}", instrs_to_string(text[start..].to_vec()))
+    }
+
+    // Handles one complete entry, writing what it prints to out
+    pub fn eval<W: Write>(&mut self, entry: &str, out: &mut W) -> io::Result<()> {
+        let entry = entry.trim();
+        let (command, rest) = match entry.strip_prefix(':') {
+            Some(command) => command.split_once(char::is_whitespace).unwrap_or((command, "")),
+            None => ("", entry),
+        };
+        match (command, parse_entry(FILE, rest)) {
+            ("", Ok(Entry::Def(def))) => self.define(def, out),
+            ("", Ok(Entry::Expr(e))) => self.run(e, out),
+            ("asm", Ok(Entry::Expr(e))) => self.asm(e, out),
+            ("asm", Ok(Entry::Def(_))) => writeln!(out, ":asm needs an expression"),
+            ("ast", Ok(Entry::Def(def))) => writeln!(out, "{:#?}", def),
+            ("ast", Ok(Entry::Expr(e))) => writeln!(out, "{:#?}", e),
+            ("" | "asm" | "ast", Err(diagnostics)) => report(out, diagnostics),
+            ("defs", _) => {
+                for def in &self.defs {
+                    writeln!(out, "{}", fundef_to_string(def))?;
+                }
+                Ok(())
+            }
+            ("help", _) => writeln!(out, "{}", HELP),
+            _ => writeln!(out, "unknown command :{}, see :help", command),
+        }
+    }
+}
+
+// Reads entries from input until :quit or the end of input
+// An entry ends at the first line where its parentheses are balanced
+pub fn run<R: BufRead, W: Write>(input: R, mut out: W, prompt: bool) -> io::Result<()> {
+    let mut repl = Repl::new();
+    let mut entry = String::new();
+    let mut lines = input.lines();
+    loop {
+        if prompt {
+            write!(out, "{}", if entry.is_empty() { "snek> " } else { "  ... " })?;
+            out.flush()?;
+        }
+        let line = match lines.next() {
+            Some(line) => line?,
+            // An unfinished entry still gets its errors reported
+            None if !entry.trim().is_empty() => return repl.eval(&entry, &mut out),
+            None => return Ok(()),
+        };
+        entry.push_str(&line);
+        entry.push('\n');
+        if !is_complete(&entry) {
+            continue;
+        }
+        match entry.trim() {
+            "" => {}
+            ":quit" | ":q" => return Ok(()),
+            _ => repl.eval(&entry, &mut out)?,
+        }
+        entry.clear();
+    }
+}
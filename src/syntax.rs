@@ -1,4 +1,21 @@
-#[derive(Debug)]
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+// A position in a source file, lines and columns start at 1
+pub struct Span {
+    pub file: Rc<str>,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Op1 {
     Add1,
     Sub1,
@@ -6,7 +23,7 @@ pub enum Op1 {
     IsBool,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Op2 {
     Plus,
     Minus,
@@ -18,8 +35,8 @@ pub enum Op2 {
     GreaterEqual,
 }
 
-#[derive(Debug)]
-pub enum Expr {
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExprKind {
     // All expressions are values
 
     // primitive values
@@ -89,17 +106,66 @@ pub enum Expr {
 
     // (<f> <e> *)
     // Calls f with the values of e_i as parameters
+    // f is the function held by a variable if one is in scope, otherwise
+    // the function defined with that name
     Call(String, Vec<Expr>),
+
+    // (lambda (<x> *) <e>)
+    // A function value, which captures the values of the variables it uses
+    // when it is made
+    Lambda(Vec<String>, Box<Expr>),
+
+    // (<f> <e> *), where f is not an identifier
+    // Calls the function value of f with the values of e_i as parameters
+    Apply(Box<Expr>, Vec<Expr>),
+
+    // Made by closure conversion, never parsed
+    // The function value of the definition f, whose captured values are in
+    // the tuple env, or false if it captures none
+    Closure(String, Box<Expr>),
+}
+
+#[derive(Clone, Debug)]
+// An expression together with the position where it starts
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
 }
 
-#[derive(Debug)]
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Expr {
+        Expr { kind, span }
+    }
+}
+
+// Expressions are equal when they have the same structure,
+// wherever they are in the source
+impl PartialEq for Expr {
+    fn eq(&self, other: &Expr) -> bool {
+        self.kind == other.kind
+    }
+}
+
+impl Eq for Expr {}
+
+#[derive(Clone, Debug)]
 pub struct FunDef {
     pub name: String,
     pub params: Vec<String>,
     pub body: Expr,
+    pub span: Span,
+}
+
+// Like expressions, definitions are compared without their positions
+impl PartialEq for FunDef {
+    fn eq(&self, other: &FunDef) -> bool {
+        self.name == other.name && self.params == other.params && self.body == other.body
+    }
 }
 
-#[derive(Debug)]
+impl Eq for FunDef {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Program {
     pub defs: Vec<FunDef>,
     pub main: Expr,
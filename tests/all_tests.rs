@@ -3,6 +3,14 @@ mod infra;
 // mod cobra_grading;
 // mod diamondback_own;
 mod input;
+mod fuzz;
+mod fmt;
+mod cli;
+mod encoder;
+mod elf;
+mod jit;
+mod repl;
+mod errors;
 
 // // Your tests go here!
 // success_tests! {
@@ -0,0 +1,228 @@
+use std::{path::PathBuf, process::Command, process::Output};
+
+use snek::driver::TempDir;
+
+fn snek(args: &[&str]) -> Output {
+    let snek: PathBuf = ["target", "debug", env!("CARGO_PKG_NAME")].iter().collect();
+    Command::new(snek).args(args).output().expect("could not run the compiler")
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8(output.stderr.clone()).unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn usage_errors() {
+    let cases: &[(&[&str], &str)] = &[
+        (&[], "no command given"),
+        (&["compile", "a.snek"], "unknown command compile"),
+        (&["check"], "check needs a file"),
+        (&["check", "a.snek", "b.snek"], "check takes a single file"),
+        (&["check", "a.snek", "-o", "a.s"], "check does not take -o"),
+        (&["emit-asm", "a.snek", "-o"], "-o needs an output file"),
+        (&["build", "a.snek", "--verbose"], "unknown option --verbose"),
+        (&["build", "a.snek", "--", "5"], "build does not take arguments after --"),
+        (&["run", "a.snek", "--", "1", "2"], "run takes a single input"),
+        (&["fmt", "--check"], "fmt needs at least one file"),
+        (&["repl", "a.snek"], "repl does not take files"),
+    ];
+    for (args, expected) in cases {
+        let output = snek(args);
+        assert_eq!(output.status.code(), Some(2), "snek {args:?} should be a usage error");
+        let stderr = stderr(&output);
+        assert!(stderr.contains(expected), "snek {args:?} printed `{stderr}`");
+        assert!(stderr.contains("Usage: snek <command>"), "snek {args:?} printed `{stderr}`");
+    }
+}
+
+#[test]
+fn help() {
+    let output = snek(&["help"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("Usage: snek <command>"));
+}
+
+#[test]
+fn check() {
+    let output = snek(&["check", "tests/input/iterate.snek"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "");
+
+    let output = snek(&["check", "tests/input/unbound_ids.snek"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("unbound_ids.snek:1:21: error: Unbound variable identifier c"));
+
+    let output = snek(&["check", "tests/input/missing.snek"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("error: could not read tests/input/missing.snek"));
+}
+
+#[test]
+fn emit_ast() {
+    let output = snek(&["emit-ast", "tests/input/simple_examples.snek"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let ast = stdout(&output);
+    assert!(ast.starts_with("Program {"), "{ast}");
+    assert!(ast.contains("TupGet("), "{ast}");
+}
+
+#[test]
+fn emit_asm() {
+    let output = snek(&["emit-asm", "tests/input/iterate.snek"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let asm = stdout(&output);
+    assert!(asm.contains("our_code_starts_here:"), "{asm}");
+    assert!(asm.contains("tup_print:"), "{asm}");
+}
+
+// Running in memory behaves like running the executable
+#[test]
+fn run_jit() {
+    let cases: &[(&str, &str)] = &[
+        ("tests/input/points.snek", "7"),
+        ("tests/input/iterate.snek", "false"),
+        ("tests/input/arith_site.snek", "false"),
+        ("tests/input/index_invalid_tuple.snek", "false"),
+        ("tests/input/gc_cycle.snek", "false"),
+        ("tests/input/out_of_memory.snek", "false"),
+        ("tests/input/stack_overflow.snek", "false"),
+        ("tests/input/index_out_of_bounds.snek", "3"),
+    ];
+    for (file, input) in cases {
+        let built = snek(&["run", file, "--", input]);
+        let jit = snek(&["run", "--jit", file, "--", input]);
+        assert_eq!(jit.status.code(), built.status.code(), "snek run --jit {file}: {}", stderr(&jit));
+        assert_eq!(stdout(&jit), stdout(&built), "snek run --jit {file}");
+        assert_eq!(stderr(&jit), stderr(&built), "snek run --jit {file}");
+    }
+    let output = snek(&["check", "--jit", "tests/input/points.snek"]);
+    assert!(stderr(&output).contains("check does not take --jit"));
+}
+
+// The executable takes the sizes of its heap and stack before the input
+#[test]
+fn runtime_sizes() {
+    let dir = TempDir::new().unwrap();
+    let deep_sum = dir.path.join("deep_sum");
+    let gc_list = dir.path.join("gc_list");
+    for (file, exe) in [("tests/input/deep_sum.snek", &deep_sum), ("tests/input/gc_list.snek", &gc_list)] {
+        let output = snek(&["build", file, "-o", exe.to_str().unwrap()]);
+        assert!(output.status.success(), "{}", stderr(&output));
+    }
+
+    // Deeper recursion than the default stack allows
+    let output = Command::new(&deep_sum).args(["--stack-size", "256m", "3000000"]).output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "4500001500000\n");
+    let output = Command::new(&deep_sum).arg("3000001").env("SNEK_STACK_SIZE", "256M").output().unwrap();
+    assert_eq!(stdout(&output), "4500004500001\n");
+    let output = Command::new(&deep_sum).arg("3000000").output().unwrap();
+    assert!(stderr(&output).contains("stack overflow"), "{}", stderr(&output));
+
+    let output = Command::new(&gc_list).args(["--heap-size", "100k"]).output().unwrap();
+    assert_eq!(stdout(&output), "(200200000, 55)\n");
+    let output = Command::new(&gc_list).env("SNEK_HEAP_SIZE", "10k").output().unwrap();
+    assert!(stderr(&output).contains("out of memory"), "{}", stderr(&output));
+
+    let output = Command::new(&gc_list).args(["--heap-size", "lots"]).output().unwrap();
+    assert!(stderr(&output).contains("Invalid --heap-size: lots"), "{}", stderr(&output));
+
+    // No room for the compiled code above the part kept for the runtime
+    let output = Command::new(&deep_sum).args(["--stack-size", "64k", "1"]).output().unwrap();
+    let error = "Invalid --stack-size: 64k is too small, it must be more than 65536 bytes";
+    assert!(stderr(&output).contains(error), "{}", stderr(&output));
+    let output = Command::new(&deep_sum).arg("1").env("SNEK_STACK_SIZE", "1k").output().unwrap();
+    assert!(stderr(&output).contains("Invalid SNEK_STACK_SIZE: 1k is too small"), "{}", stderr(&output));
+    let output = Command::new(&deep_sum).args(["--stack-size", "1m", "1"]).output().unwrap();
+    assert_eq!(stdout(&output), "1\n");
+}
+
+#[test]
+fn backtraces() {
+    let dir = TempDir::new().unwrap();
+    let exe = dir.path.join("backtrace");
+    let output = snek(&["build", "tests/input/backtrace.snek", "-o", exe.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let error = "error 2 at tests/input/backtrace.snek:3:5: + expected a number but got false\n";
+
+    let output = Command::new(&exe).output().unwrap();
+    assert_eq!(stderr(&output), error);
+    let output = Command::new(&exe).env("SNEK_BACKTRACE", "1").output().unwrap();
+    let frames = "  in fact at tests/input/backtrace.snek:3:5
+  in fact at tests/input/backtrace.snek:4:10
+  in fact at tests/input/backtrace.snek:4:10
+  in main at tests/input/backtrace.snek:5:1
+";
+    assert_eq!(stderr(&output), format!("{}{}", error, frames));
+}
+
+fn tool(tool: &str, args: &[&str]) -> String {
+    let output = Command::new(tool).args(args).output().expect("could not run binutils");
+    assert!(output.status.success(), "{}", stderr(&output));
+    stdout(&output)
+}
+
+// The linked executable has the compile unit of the snek source, and its
+// line table maps the code of each function to the lines it comes from
+#[test]
+fn executables_have_line_info() {
+    let dir = TempDir::new().unwrap();
+    let exe = dir.path.join("backtrace");
+    let output = snek(&["build", "tests/input/backtrace.snek", "-o", exe.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let exe = exe.to_str().unwrap();
+
+    let info = tool("readelf", &["--debug-dump=info", exe]);
+    assert!(info.contains("DW_AT_name        : tests/input/backtrace.snek"), "{info}");
+    assert!(info.contains("DW_AT_comp_dir    : "), "{info}");
+
+    let symbols = tool("nm", &[exe]);
+    let address = |name: &str| {
+        let line = symbols.lines().find(|l| l.ends_with(&format!(" {}", name))).unwrap();
+        u64::from_str_radix(line.split_whitespace().next().unwrap(), 16).unwrap()
+    };
+    let (fact, main) = (address("fact"), address("our_code_starts_here"));
+
+    // Rows of the snek file: the line, then the address it starts at
+    let lines = tool("objdump", &["--dwarf=decodedline", exe]);
+    let rows: Vec<(u32, u64)> = lines
+        .lines()
+        .filter(|l| l.starts_with("tests/input/backtrace.snek"))
+        .filter_map(|l| {
+            let fields: Vec<&str> = l.split_whitespace().collect();
+            let address = u64::from_str_radix(fields[2].trim_start_matches("0x"), 16).ok()?;
+            Some((fields[1].parse().ok()?, address))
+        })
+        .collect();
+    let lines_of = |start: u64, end: u64| {
+        let mut lines: Vec<u32> = rows.iter().filter(|(_, a)| (start..end).contains(a)).map(|(l, _)| *l).collect();
+        lines.dedup();
+        lines
+    };
+    assert_eq!(rows.first(), Some(&(1, fact)), "{lines}");
+    assert_eq!(lines_of(fact, main), vec![1, 2, 3, 4], "{lines}");
+    assert_eq!(lines_of(main, u64::MAX), vec![5], "{lines}");
+}
+
+// Needs gdb, so it only runs with cargo test -- --ignored
+#[test]
+#[ignore]
+fn gdb_breaks_on_snek_lines() {
+    let dir = TempDir::new().unwrap();
+    let exe = dir.path.join("backtrace");
+    let output = snek(&["build", "tests/input/backtrace.snek", "-o", exe.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let output = Command::new("gdb")
+        .args(["-batch", "-ex", "break backtrace.snek:3", "-ex", "info symbol fact"])
+        .arg(&exe)
+        .output()
+        .expect("could not run gdb");
+    let out = stdout(&output);
+    assert!(out.contains("file tests/input/backtrace.snek, line 3."), "{out}{}", stderr(&output));
+    assert!(out.contains("fact in section .text"), "{out}");
+}
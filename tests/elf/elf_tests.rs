@@ -0,0 +1,68 @@
+use std::{fs, process::Command};
+
+use snek::compiler::compile_asm;
+use snek::driver::TempDir;
+use snek::elf::{archive, object};
+use snek::parser::parse;
+
+fn tool_output(tool: &str, args: &[&str]) -> String {
+    let output = Command::new(tool).args(args).output().expect("could not run binutils");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+// The object defines what the runtime needs, and leaves the calls into it to the linker
+#[test]
+fn object_symbols_and_relocations() {
+    let prog = parse("f.snek", "(fun (f x) (print x)) (f (+ input 1))").unwrap();
+    let dir = TempDir::new().unwrap();
+    let obj = dir.path.join("our_code.o");
+    fs::write(&obj, object(&compile_asm(&prog))).unwrap();
+    let obj = obj.to_str().unwrap();
+
+    let symbols = tool_output("nm", &[obj]);
+    for expected in [
+        "T our_code_starts_here",
+        "R snek_site_table",
+        "R snek_source_file",
+        "t f",
+        "U snek_error",
+        "U snek_print",
+    ] {
+        assert!(symbols.contains(expected), "no {expected} in\n{symbols}");
+    }
+
+    let relocs = tool_output("readelf", &["-r", obj]);
+    assert_eq!(relocs.matches("R_X86_64_PLT32").count(), 2, "{relocs}");
+    assert!(relocs.contains("snek_print - 4"), "{relocs}");
+}
+
+// Debuggers find the snek line of the code, and where each function ends
+#[test]
+fn object_has_line_info() {
+    let prog = parse("f.snek", "(fun (f x)\n  (print x))\n(f\n  (+ input 1))").unwrap();
+    let dir = TempDir::new().unwrap();
+    let obj = dir.path.join("our_code.o");
+    fs::write(&obj, object(&compile_asm(&prog))).unwrap();
+    let obj = obj.to_str().unwrap();
+
+    let lines = tool_output("objdump", &["--dwarf=decodedline", obj]);
+    for line in 1..=4 {
+        let row = ["f.snek".to_string(), line.to_string()];
+        let found = lines.lines().any(|l| l.split_whitespace().take(2).eq(row.iter().map(|s| s.as_str())));
+        assert!(found, "no line {line} in\n{lines}");
+    }
+    let symbols = tool_output("readelf", &["-s", obj]);
+    let f = symbols.lines().find(|l| l.ends_with(" f")).unwrap();
+    assert!(f.contains("FUNC") && !f.contains(" 0 FUNC"), "{symbols}");
+}
+
+#[test]
+fn archive_has_an_index() {
+    let prog = parse("f.snek", "(+ input 1)").unwrap();
+    let dir = TempDir::new().unwrap();
+    let lib = dir.path.join("libour_code.a");
+    fs::write(&lib, archive("our_code.o", &object(&compile_asm(&prog)))).unwrap();
+    let index = tool_output("nm", &["-s", lib.to_str().unwrap()]);
+    assert!(index.contains("our_code_starts_here in our_code.o"), "{index}");
+}
@@ -0,0 +1 @@
+mod elf_tests;
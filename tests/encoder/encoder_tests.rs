@@ -0,0 +1,202 @@
+use std::{fs, path::Path, process::Command};
+
+use snek::asm::*;
+use snek::check::check;
+use snek::compiler::{compile, compile_asm};
+use snek::driver::TempDir;
+use snek::encoder::{encode, Reloc};
+use snek::parser::parse;
+
+use crate::infra::snek_files;
+
+fn rax() -> Arg {
+    Arg::Reg(Reg::Rax)
+}
+
+fn bytes(i: Instr) -> Vec<u8> {
+    encode(&[i]).bytes
+}
+
+// The bytes nasm assembles each form to
+#[test]
+fn encodes_like_nasm() {
+    let stack = |disp| Arg::Mem(maddr_bd(Reg::Rsp, disp));
+    let cases: Vec<(Instr, Vec<u8>)> = vec![
+        (Instr::Mov(rax(), Arg::Imm(5)), vec![0xB8, 5, 0, 0, 0]),
+        (Instr::Mov(Arg::Reg(Reg::R15), Arg::Imm(5)), vec![0x41, 0xBF, 5, 0, 0, 0]),
+        (Instr::Mov(Arg::Reg(Reg::Rcx), Arg::Imm(-2)), vec![0x48, 0xC7, 0xC1, 0xFE, 0xFF, 0xFF, 0xFF]),
+        (
+            Instr::Mov(rax(), Arg::Imm(1 << 40)),
+            vec![0x48, 0xB8, 0, 0, 0, 0, 0, 1, 0, 0],
+        ),
+        (Instr::Mov(Arg::Reg(Reg::R15), Arg::Reg(Reg::Rsi)), vec![0x49, 0x89, 0xF7]),
+        (Instr::Mov(rax(), stack(-16)), vec![0x48, 0x8B, 0x44, 0x24, 0xF0]),
+        (Instr::Mov(stack(-1024), rax()), vec![0x48, 0x89, 0x84, 0x24, 0x00, 0xFC, 0xFF, 0xFF]),
+        (Instr::Mov(Arg::Mem(maddr_b(Reg::R15)), rax()), vec![0x49, 0x89, 0x07]),
+        (Instr::Mov(Arg::Mem(maddr_b(Reg::Rbp)), rax()), vec![0x48, 0x89, 0x45, 0x00]),
+        (
+            Instr::Mov(rax(), Arg::Mem(maddr_bisd(Reg::Rax, Reg::Rbx, 8, 0))),
+            vec![0x48, 0x8B, 0x04, 0xD8],
+        ),
+        (
+            Instr::Mov(rax(), Arg::Mem(maddr_bisd(Reg::R15, Reg::Rcx, 2, 8))),
+            vec![0x49, 0x8B, 0x44, 0x4F, 0x08],
+        ),
+        (Instr::Mov(stack(-8), Arg::Imm(7)), vec![0x48, 0xC7, 0x44, 0x24, 0xF8, 7, 0, 0, 0]),
+        (Instr::Add(rax(), Arg::Imm(2)), vec![0x48, 0x83, 0xC0, 0x02]),
+        (Instr::Add(rax(), Arg::Imm(1000)), vec![0x48, 0x05, 0xE8, 0x03, 0, 0]),
+        (Instr::Add(Arg::Reg(Reg::R15), Arg::Imm(1000)), vec![0x49, 0x81, 0xC7, 0xE8, 0x03, 0, 0]),
+        (Instr::Add(rax(), stack(-16)), vec![0x48, 0x03, 0x44, 0x24, 0xF0]),
+        (Instr::Sub(stack(-16), rax()), vec![0x48, 0x29, 0x44, 0x24, 0xF0]),
+        (Instr::And(Arg::Reg(Reg::Rsp), Arg::Imm(-16)), vec![0x48, 0x83, 0xE4, 0xF0]),
+        (Instr::Or(rax(), Arg::Reg(Reg::Rcx)), vec![0x48, 0x09, 0xC8]),
+        (Instr::Xor(Arg::Reg(Reg::Rcx), rax()), vec![0x48, 0x31, 0xC1]),
+        (Instr::Cmp(Arg::Reg(Reg::Rcx), Arg::Imm(1)), vec![0x48, 0x83, 0xF9, 0x01]),
+        (Instr::Cmp(stack(-16), rax()), vec![0x48, 0x39, 0x44, 0x24, 0xF0]),
+        (Instr::Test(rax(), Arg::Imm(1)), vec![0x48, 0xA9, 1, 0, 0, 0]),
+        (Instr::Test(Arg::Reg(Reg::Rcx), Arg::Imm(1)), vec![0x48, 0xF7, 0xC1, 1, 0, 0, 0]),
+        (Instr::Test(rax(), Arg::Reg(Reg::Rcx)), vec![0x48, 0x85, 0xC8]),
+        (Instr::Imul(rax(), stack(-16)), vec![0x48, 0x0F, 0xAF, 0x44, 0x24, 0xF0]),
+        (Instr::Imul(rax(), Arg::Imm(3)), vec![0x48, 0x6B, 0xC0, 0x03]),
+        (Instr::Shl(rax(), Arg::Imm(1)), vec![0x48, 0xD1, 0xE0]),
+        (Instr::Sar(rax(), Arg::Imm(1)), vec![0x48, 0xD1, 0xF8]),
+        (Instr::Sar(rax(), Arg::Imm(3)), vec![0x48, 0xC1, 0xF8, 0x03]),
+        (Instr::Shl(rax(), Arg::Reg(Reg::Rcx)), vec![0x48, 0xD3, 0xE0]),
+        (Instr::Cmove(rax(), Arg::Reg(Reg::Rbx)), vec![0x48, 0x0F, 0x44, 0xC3]),
+        (Instr::Cmovl(rax(), Arg::Reg(Reg::Rbx)), vec![0x48, 0x0F, 0x4C, 0xC3]),
+        (Instr::Cmovle(rax(), Arg::Reg(Reg::Rbx)), vec![0x48, 0x0F, 0x4E, 0xC3]),
+        (Instr::Cmovg(rax(), Arg::Reg(Reg::Rbx)), vec![0x48, 0x0F, 0x4F, 0xC3]),
+        (Instr::Cmovge(rax(), Arg::Reg(Reg::Rbx)), vec![0x48, 0x0F, 0x4D, 0xC3]),
+        (Instr::Push(Reg::Rbx), vec![0x53]),
+        (Instr::Push(Reg::R15), vec![0x41, 0x57]),
+        (Instr::Pop(Reg::R13), vec![0x41, 0x5D]),
+        (Instr::Ret, vec![0xC3]),
+        (Instr::CallReg(Reg::Rax), vec![0xFF, 0xD0]),
+        (Instr::JmpReg(Reg::Rcx), vec![0xFF, 0xE1]),
+    ];
+    for (instr, expected) in cases {
+        assert_eq!(bytes(instr.clone()), expected, "{instr:?}");
+    }
+}
+
+#[test]
+fn jumps_grow_when_they_do_not_reach() {
+    let label = |l: &str| Instr::Label(l.to_string());
+    let near = vec![
+        label("top"),
+        Instr::Jne("top".to_string()),
+        Instr::Jle("top".to_string()),
+        Instr::Jb("top".to_string()),
+        Instr::Jmp("top".to_string()),
+    ];
+    assert_eq!(encode(&near).bytes, vec![0x75, 0xFE, 0x7E, 0xFC, 0x72, 0xFA, 0xEB, 0xF8]);
+
+    // 40 instructions of 4 bytes are too far for an 8-bit offset
+    let mut far = vec![Instr::Je("end".to_string()), Instr::Jmp("end".to_string())];
+    far.extend((0..40).map(|_| Instr::Add(rax(), Arg::Imm(2))));
+    far.push(label("end"));
+    let code = encode(&far);
+    assert_eq!(code.bytes[..11], [0x0F, 0x84, 165, 0, 0, 0, 0xE9, 160, 0, 0, 0]);
+    assert_eq!(code.labels["end"], 171);
+}
+
+#[test]
+fn calls_to_other_code_are_relocated() {
+    let code = encode(&[
+        Instr::Label("f".to_string()),
+        Instr::Call("f".to_string()),
+        Instr::Call("snek_print".to_string()),
+    ]);
+    assert_eq!(code.bytes, vec![0xE8, 0xFB, 0xFF, 0xFF, 0xFF, 0xE8, 0, 0, 0, 0]);
+    assert_eq!(code.relocs, vec![Reloc { offset: 6, symbol: "snek_print".to_string(), addend: -4 }]);
+}
+
+#[test]
+fn lea_takes_addresses_relative_to_the_next_instruction() {
+    let code = encode(&[
+        Instr::Label("f".to_string()),
+        Instr::Lea(Reg::Rax, "f".to_string()),
+        Instr::Lea(Reg::Rcx, "g".to_string()),
+        Instr::Label("g".to_string()),
+    ]);
+    assert_eq!(code.bytes, vec![0x48, 0x8D, 0x05, 0xF9, 0xFF, 0xFF, 0xFF, 0x48, 0x8D, 0x0D, 0, 0, 0, 0]);
+}
+
+// Assembles asm with nasm and returns the contents of its .text section
+fn nasm_text(asm: &str) -> Vec<u8> {
+    let dir = TempDir::new().unwrap();
+    let (asm_path, obj_path, bin_path) =
+        (dir.path.join("a.s"), dir.path.join("a.o"), dir.path.join("a.bin"));
+    fs::write(&asm_path, asm).unwrap();
+    let status = Command::new("nasm")
+        .args(["-f", "elf64"])
+        .arg(&asm_path)
+        .arg("-o")
+        .arg(&obj_path)
+        .status()
+        .expect("could not run nasm");
+    assert!(status.success());
+    let status = Command::new("objcopy")
+        .args(["-O", "binary", "--only-section=.text"])
+        .arg(&obj_path)
+        .arg(&bin_path)
+        .status()
+        .expect("could not run objcopy");
+    assert!(status.success());
+    fs::read(&bin_path).unwrap()
+}
+
+// Every valid program in the test suite encodes to the same bytes nasm
+// produces, with the relocated fields left as zeros like nasm leaves them
+// Needs nasm, so it only runs with cargo test -- --ignored
+#[test]
+#[ignore]
+fn corpus_matches_nasm() {
+    let mut files = vec![];
+    snek_files(Path::new("tests"), &mut files);
+    for file in files {
+        let name = file.to_str().unwrap();
+        let contents = fs::read_to_string(&file).unwrap();
+        let Ok(prog) = parse(name, &contents) else { continue };
+        if check(&prog).is_err() {
+            continue;
+        }
+        let code = encode(&compile_asm(&prog).text);
+        assert!(code.bytes == nasm_text(&compile(&prog)), "{name} encodes differently than nasm");
+    }
+}
+
+// The assembly has no operand sizes, so an instruction can only take a
+// memory operand along with a register, which gives its size
+#[test]
+fn memory_operands_have_sized_partners() {
+    let mut files = vec![];
+    snek_files(Path::new("tests"), &mut files);
+    for file in files {
+        let name = file.to_str().unwrap();
+        let contents = fs::read_to_string(&file).unwrap();
+        let Ok(prog) = parse(name, &contents) else { continue };
+        if check(&prog).is_err() {
+            continue;
+        }
+        for line in compile(&prog).lines() {
+            let operands: Vec<&str> = line.split_once(' ').map_or(vec![], |(_, ops)| ops.split(", ").collect());
+            if let [dst, src] = operands[..] {
+                let unsized_mem = dst.starts_with('[') && src.parse::<i64>().is_ok();
+                assert!(!unsized_mem, "{name} compiles to {line:?}, which nasm cannot size");
+            }
+        }
+    }
+}
+
+// Calls of function values, in the assembly that snek emit-asm writes
+// Needs nasm, so it only runs with cargo test -- --ignored
+#[test]
+#[ignore]
+fn function_value_calls_assemble_with_nasm() {
+    for name in ["tests/input/fun_values.snek", "tests/input/closures.snek"] {
+        let prog = parse(name, &fs::read_to_string(name).unwrap()).unwrap();
+        let code = encode(&compile_asm(&prog).text);
+        assert!(code.bytes == nasm_text(&compile(&prog)), "{name} encodes differently than nasm");
+    }
+}
@@ -0,0 +1 @@
+mod encoder_tests;
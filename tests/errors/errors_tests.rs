@@ -0,0 +1,27 @@
+use std::collections::HashSet;
+
+use snek::errors::{operation, SnekError};
+
+#[test]
+fn codes_and_names_are_unique() {
+    let mut names = HashSet::new();
+    for error in SnekError::ALL {
+        assert_eq!(SnekError::from_code(error.code()), Some(error));
+        assert!(names.insert(error.name()), "{} is used twice", error.name());
+    }
+    assert_eq!(SnekError::from_code(0), None);
+}
+
+#[test]
+fn messages_fill_in_their_operands() {
+    assert_eq!(SnekError::Overflow.message(&[]), "overflow");
+    let message = SnekError::IndexOutOfBounds.message(&["-1".to_string(), "3".to_string()]);
+    assert_eq!(message, "index -1 out of bounds for tuple of length 3");
+}
+
+#[test]
+fn type_errors_name_the_operation_and_value() {
+    let show = |v: u64| format!("{}", v as i64 >> 1);
+    let operands = unsafe { SnekError::InvalidArith.operands(6, operation("add1") as u64, show) };
+    assert_eq!(SnekError::InvalidArith.message(&operands), "add1 expected a number but got 3");
+}
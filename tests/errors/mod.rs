@@ -0,0 +1 @@
+mod errors_tests;
@@ -0,0 +1,82 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use snek::driver::TempDir;
+use snek::parser::parse;
+use snek::printer::format;
+use snek::reader::comments;
+
+use crate::infra::snek_files;
+
+fn format_file(file: &Path) -> String {
+    let name = file.to_str().unwrap();
+    let contents = fs::read_to_string(file).unwrap();
+    let prog = parse(name, &contents).unwrap();
+    format(&prog, &comments(name, &contents))
+}
+
+// Every program in the test suite that parses should parse back to the
+// same program after formatting, and formatting it again changes nothing
+#[test]
+fn round_trip_test_programs() {
+    let mut files = vec![];
+    snek_files(Path::new("tests"), &mut files);
+    for file in files {
+        let name = file.to_str().unwrap();
+        let contents = fs::read_to_string(&file).unwrap();
+        let Ok(prog) = parse(name, &contents) else { continue };
+        let formatted = format(&prog, &comments(name, &contents));
+        let reparsed = parse(name, &formatted).unwrap_or_else(|diags| {
+            panic!("formatted {name} does not parse: {}\n{formatted}", diags[0])
+        });
+        assert_eq!(reparsed, prog, "formatting {name} changed the program");
+        let again = format(&reparsed, &comments(name, &formatted));
+        assert_eq!(again, formatted, "formatting {name} is not idempotent");
+    }
+}
+
+#[test]
+fn comments_are_preserved() {
+    let expected = fs::read_to_string("tests/fmt/comments_formatted.snek").unwrap();
+    let formatted = format_file(Path::new("tests/fmt/comments.snek"));
+    assert_eq!(formatted, expected);
+}
+
+#[test]
+fn fmt_check() {
+    let snek: PathBuf = ["target", "debug", env!("CARGO_PKG_NAME")].iter().collect();
+    let output = Command::new(&snek)
+        .args(["fmt", "--check", "tests/fmt/comments_formatted.snek"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "a formatted file failed the check");
+
+    let output = Command::new(&snek)
+        .args(["fmt", "--check", "tests/fmt/comments.snek"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success(), "an unformatted file passed the check");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("tests/fmt/comments.snek is not formatted"), "{stderr}");
+}
+
+// Forms after the main expression are errors, so fmt must not drop them
+#[test]
+fn fmt_keeps_forms_after_main() {
+    let snek: PathBuf = ["target", "debug", env!("CARGO_PKG_NAME")].iter().collect();
+    let contents = fs::read_to_string("tests/fmt/extra_forms.snek").unwrap();
+    let dir = TempDir::new().unwrap();
+    let file = dir.path.join("extra_forms.snek");
+    fs::write(&file, &contents).unwrap();
+    let output = Command::new(&snek).arg("fmt").arg(&file).output().unwrap();
+    let after = fs::read_to_string(&file).unwrap();
+
+    assert!(!output.status.success(), "fmt accepted forms after the main expression");
+    assert_eq!(after, contents, "fmt rewrote a file it rejected");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("3:1: error: Invalid program: unexpected form after the main expression"), "{stderr}");
+    assert!(stderr.contains("4:1: error: Invalid program: unexpected form after the main expression"), "{stderr}");
+}
@@ -0,0 +1 @@
+mod fmt_tests;
@@ -0,0 +1,136 @@
+use std::{
+    env, fs, io,
+    os::unix::process::ExitStatusExt,
+    path::Path,
+    process::{Command, Output, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use snek::check::check;
+use snek::errors::SnekError;
+use snek::interp::{self, Interp, RuntimeError};
+use snek::parser::parse;
+use snek::printer::program_to_string;
+use snek::syntax::Program;
+
+use super::generator::Generator;
+use super::shrink::shrink;
+use crate::infra;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn env_or(var: &str, default: u64) -> u64 {
+    env::var(var).ok().and_then(|s| s.parse().ok()).unwrap_or(default)
+}
+
+// Runs a compiled program, killing it if it takes longer than TIMEOUT
+// Shrinking can turn loops into infinite ones, and those are not crashes
+fn run_with_timeout(name: &str, input: &str) -> Option<Output> {
+    let mut child = Command::new(Path::new("tests").join(format!("{name}.run")))
+        .arg(input)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("could not run the program");
+    let start = Instant::now();
+    while child.try_wait().unwrap().is_none() {
+        if start.elapsed() > TIMEOUT {
+            child.kill().unwrap();
+            child.wait().unwrap();
+            return None;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Some(child.wait_with_output().unwrap())
+}
+
+// Compiles and runs p, and describes how it crashed, if it did
+// A crash is the compiler failing on a valid program, or the program
+// exiting in any way other than normally or through snek_error
+fn crash(name: &str, p: &Program, input: &str) -> Option<String> {
+    let file = Path::new("tests").join(format!("{name}.snek"));
+    fs::write(&file, program_to_string(p)).unwrap();
+    if let Err(err) = infra::compile(name, &file) {
+        return Some(format!("the compiler failed: {err}"));
+    }
+    let output = run_with_timeout(name, input)?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    match (output.status.code(), output.status.signal()) {
+        (Some(0 | 1), _) => None,
+        (_, Some(signal)) => Some(format!("killed by signal {signal}")),
+        (code, _) => Some(format!("exited with {code:?}: {stderr}")),
+    }
+}
+
+// Runs SNEK_FUZZ_ITERS random programs starting from SNEK_FUZZ_SEED:
+// cargo test fuzz_compiler -- --ignored
+// The first crash is minimized and saved to tests/fuzz_crash_<seed>.snek
+#[test]
+#[ignore]
+fn fuzz_compiler() {
+    let iters = env_or("SNEK_FUZZ_ITERS", 100);
+    let seed = env_or("SNEK_FUZZ_SEED", 0);
+    let name = "fuzz_case";
+    for i in 0..iters {
+        let case_seed = seed + i;
+        let (p, input) = Generator::new(case_seed, i % 2 == 1).program();
+        if crash(name, &p, &input).is_some() {
+            let p = shrink(p, |p| crash(name, p, &input).is_some());
+            let err = crash(name, &p, &input).unwrap();
+            let file = format!("tests/fuzz_crash_{case_seed}.snek");
+            fs::write(&file, program_to_string(&p)).unwrap();
+            panic!("seed {case_seed} crashed with input {input}: {err}\nminimized program in {file}");
+        }
+    }
+}
+
+#[test]
+fn generated_programs_round_trip() {
+    for seed in 0..200 {
+        let (p, _) = Generator::new(seed, seed % 2 == 1).program();
+        let text = program_to_string(&p);
+        let parsed = parse("fuzz.snek", &text).unwrap_or_else(|diags| {
+            panic!("seed {seed} does not parse: {}\n{text}", diags[0])
+        });
+        if let Err(diags) = check(&parsed) {
+            panic!("seed {seed} does not pass the checker: {}\n{text}", diags[0]);
+        }
+        assert_eq!(parsed, p, "seed {seed} parsed back to a different program");
+        assert_eq!(program_to_string(&parsed), text, "seed {seed} printed differently");
+    }
+}
+
+#[test]
+fn well_typed_programs_only_overflow() {
+    for seed in 0..200 {
+        let (p, input) = Generator::new(seed, false).program();
+        let input = interp::parse_input(&input).unwrap();
+        match interp::run(&p, input, io::sink()) {
+            Ok(()) | Err(RuntimeError::Snek { error: SnekError::Overflow, .. }) => {}
+            Err(err) => panic!("seed {seed} failed with {err}\n{}", program_to_string(&p)),
+        }
+    }
+}
+
+#[test]
+fn shrink_keeps_failure() {
+    // Shrinking can turn loops into infinite ones, so evaluation is bounded
+    let fails = |p: &Program, input: &str| {
+        let input = interp::parse_input(input).unwrap();
+        let mut interp = Interp::new(p, input, io::sink()).with_step_limit(10_000);
+        matches!(interp.eval_main(&p.main), Err(RuntimeError::Snek { error: SnekError::InvalidArith, .. }))
+    };
+    let mut shrunk = 0;
+    for seed in 0..100 {
+        let (p, input) = Generator::new(seed, true).program();
+        if !fails(&p, &input) {
+            continue;
+        }
+        let small = shrink(p.clone(), |p| fails(p, &input));
+        assert!(fails(&small, &input), "seed {seed} no longer fails after shrinking");
+        assert!(program_to_string(&small).len() <= program_to_string(&p).len());
+        shrunk += 1;
+    }
+    assert!(shrunk > 0, "no generated program had a type error");
+}
@@ -0,0 +1,320 @@
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+use snek::syntax::*;
+
+#[derive(Clone, Debug, PartialEq)]
+// The static type of a generated expression
+pub enum Ty {
+    Num,
+    Bool,
+    Tup(Vec<Ty>),
+}
+
+// The signature of a generated function
+// Every function takes a fuel parameter first, and only recurses when it is positive
+struct Sig {
+    name: String,
+    params: Vec<Ty>,
+    ret: Ty,
+}
+
+#[derive(Clone)]
+struct Var {
+    name: String,
+    ty: Ty,
+    // loop counters and fuel are never set!, so that programs terminate
+    mutable: bool,
+}
+
+#[derive(Clone)]
+// What is in scope where an expression is generated
+struct Ctx {
+    vars: Vec<Var>,
+    // The fuel parameter, if generating the recursive case of a function
+    fuel: Option<String>,
+    can_call: bool,
+}
+
+fn mk(kind: ExprKind) -> Expr {
+    Expr::new(kind, Span::default())
+}
+
+fn num(n: i64) -> Expr {
+    mk(ExprKind::Number(n))
+}
+
+fn var(id: &str) -> Expr {
+    mk(ExprKind::Var(id.to_string()))
+}
+
+// Generates random programs that terminate and pass the checker
+// Well-typed programs can only fail at runtime with an overflow.
+// Ill-typed ones have some subexpressions replaced by values of a random type
+pub struct Generator {
+    rng: StdRng,
+    // The probability of generating a value of a random type instead of the expected one
+    ill_typed: f64,
+    sigs: Vec<Sig>,
+    fresh: usize,
+}
+
+impl Generator {
+    pub fn new(seed: u64, ill_typed: bool) -> Generator {
+        Generator {
+            rng: StdRng::seed_from_u64(seed),
+            ill_typed: if ill_typed { 0.05 } else { 0.0 },
+            sigs: vec![],
+            fresh: 0,
+        }
+    }
+
+    fn fresh(&mut self, prefix: &str) -> String {
+        self.fresh += 1;
+        format!("{}{}", prefix, self.fresh)
+    }
+
+    fn ty(&mut self, depth: usize) -> Ty {
+        match self.rng.gen_range(0..10) {
+            0..=4 => Ty::Num,
+            5..=7 => Ty::Bool,
+            _ if depth == 0 => Ty::Num,
+            _ => {
+                let n = self.rng.gen_range(1..=3);
+                Ty::Tup((0..n).map(|_| self.ty(depth - 1)).collect())
+            }
+        }
+    }
+
+    fn number(&mut self) -> i64 {
+        // Mostly small numbers, sometimes close to the bounds to provoke overflows
+        if self.rng.gen_ratio(1, 20) {
+            *[(1 << 62) - 1, -(1 << 62), 1 << 61].choose(&mut self.rng).unwrap()
+        } else {
+            self.rng.gen_range(-20..=20)
+        }
+    }
+
+    fn var_of(&mut self, ty: &Ty, ctx: &Ctx, mutable: bool) -> Option<String> {
+        let vars: Vec<&Var> = ctx
+            .vars
+            .iter()
+            .filter(|v| &v.ty == ty && (v.mutable || !mutable))
+            .collect();
+        vars.choose(&mut self.rng).map(|v| v.name.clone())
+    }
+
+    fn leaf(&mut self, ty: &Ty, ctx: &Ctx) -> Expr {
+        if self.rng.gen_bool(0.5) {
+            if let Some(id) = self.var_of(ty, ctx, false) {
+                return var(&id);
+            }
+        }
+        match ty {
+            Ty::Num => num(self.number()),
+            Ty::Bool => mk(ExprKind::Boolean(self.rng.gen())),
+            Ty::Tup(tys) => mk(ExprKind::Tup(tys.iter().map(|ty| self.leaf(ty, ctx)).collect())),
+        }
+    }
+
+    fn expr(&mut self, ty: &Ty, ctx: &Ctx, depth: usize) -> Expr {
+        let ty = if self.rng.gen_bool(self.ill_typed) { self.ty(1) } else { ty.clone() };
+        if depth == 0 {
+            return self.leaf(&ty, ctx);
+        }
+        let e = match self.rng.gen_range(0..12) {
+            0 => Some(self.gen_let(&ty, ctx, depth)),
+            1 => {
+                let cond = self.expr(&Ty::Bool, ctx, depth - 1);
+                let thn = self.expr(&ty, ctx, depth - 1);
+                let els = self.expr(&ty, ctx, depth - 1);
+                Some(mk(ExprKind::If(Box::new(cond), Box::new(thn), Box::new(els))))
+            }
+            2 => {
+                let mut es = vec![];
+                for _ in 0..self.rng.gen_range(0..3) {
+                    let ty = self.ty(1);
+                    es.push(self.expr(&ty, ctx, depth - 1));
+                }
+                es.push(self.expr(&ty, ctx, depth - 1));
+                Some(mk(ExprKind::Block(es)))
+            }
+            3 => Some(mk(ExprKind::Print(Box::new(self.expr(&ty, ctx, depth - 1))))),
+            4 => self.var_of(&ty, ctx, true).map(|id| {
+                mk(ExprKind::Set(id, Box::new(self.expr(&ty, ctx, depth - 1))))
+            }),
+            5 => Some(self.gen_loop(&ty, ctx, depth)),
+            6 | 7 => self.gen_call(&ty, ctx, depth),
+            8 => {
+                let mut tys: Vec<Ty> = (0..self.rng.gen_range(0..3)).map(|_| self.ty(1)).collect();
+                let i = self.rng.gen_range(0..=tys.len());
+                tys.insert(i, ty.clone());
+                let t = self.expr(&Ty::Tup(tys), ctx, depth - 1);
+                Some(mk(ExprKind::TupGet(Box::new(t), Box::new(num(i as i64)))))
+            }
+            _ => None,
+        };
+        e.unwrap_or_else(|| self.typed(&ty, ctx, depth))
+    }
+
+    // Forms that can only produce a value of type ty
+    fn typed(&mut self, ty: &Ty, ctx: &Ctx, depth: usize) -> Expr {
+        match ty {
+            Ty::Num => match self.rng.gen_range(0..5) {
+                0 => {
+                    let op = if self.rng.gen() { Op1::Add1 } else { Op1::Sub1 };
+                    mk(ExprKind::UnOp(op, Box::new(self.expr(&Ty::Num, ctx, depth - 1))))
+                }
+                1 => {
+                    let ty = Ty::Tup((0..self.rng.gen_range(0..3)).map(|_| self.ty(1)).collect());
+                    mk(ExprKind::TupLen(Box::new(self.expr(&ty, ctx, depth - 1))))
+                }
+                2 => num(self.number()),
+                _ => {
+                    let op = [Op2::Plus, Op2::Minus, Op2::Times].choose(&mut self.rng).unwrap().clone();
+                    let e1 = self.expr(&Ty::Num, ctx, depth - 1);
+                    let e2 = self.expr(&Ty::Num, ctx, depth - 1);
+                    mk(ExprKind::BinOp(op, Box::new(e1), Box::new(e2)))
+                }
+            },
+            Ty::Bool => match self.rng.gen_range(0..3) {
+                0 => {
+                    let op = if self.rng.gen() { Op1::IsNum } else { Op1::IsBool };
+                    let ty = self.ty(1);
+                    mk(ExprKind::UnOp(op, Box::new(self.expr(&ty, ctx, depth - 1))))
+                }
+                1 => {
+                    let ty = self.ty(1);
+                    let e1 = self.expr(&ty, ctx, depth - 1);
+                    let e2 = self.expr(&ty, ctx, depth - 1);
+                    mk(ExprKind::BinOp(Op2::Equal, Box::new(e1), Box::new(e2)))
+                }
+                _ => {
+                    let ops = [Op2::Less, Op2::LessEqual, Op2::Greater, Op2::GreaterEqual];
+                    let op = ops.choose(&mut self.rng).unwrap().clone();
+                    let e1 = self.expr(&Ty::Num, ctx, depth - 1);
+                    let e2 = self.expr(&Ty::Num, ctx, depth - 1);
+                    mk(ExprKind::BinOp(op, Box::new(e1), Box::new(e2)))
+                }
+            },
+            Ty::Tup(tys) => {
+                if !tys.is_empty() && self.rng.gen_bool(0.3) {
+                    let i = self.rng.gen_range(0..tys.len());
+                    let t = self.expr(ty, ctx, depth - 1);
+                    let e = self.expr(&tys[i], ctx, depth - 1);
+                    mk(ExprKind::TupSet(Box::new(t), Box::new(num(i as i64)), Box::new(e)))
+                } else {
+                    let es = tys.iter().map(|ty| self.expr(ty, ctx, depth - 1)).collect();
+                    mk(ExprKind::Tup(es))
+                }
+            }
+        }
+    }
+
+    fn gen_let(&mut self, ty: &Ty, ctx: &Ctx, depth: usize) -> Expr {
+        let mut new_ctx = ctx.clone();
+        let mut bindings = vec![];
+        for _ in 0..self.rng.gen_range(1..=3) {
+            let var_ty = self.ty(2);
+            let e = self.expr(&var_ty, &new_ctx, depth - 1);
+            let name = self.fresh("x");
+            new_ctx.vars.push(Var { name: name.clone(), ty: var_ty, mutable: true });
+            bindings.push((name, e));
+        }
+        let body = self.expr(ty, &new_ctx, depth - 1);
+        mk(ExprKind::Let(bindings, Box::new(body)))
+    }
+
+    // A loop that runs at most a few times:
+    // (let ((i 0)) (loop (if (>= i n) (break e) (block ... (set! i (add1 i))))))
+    // where the block may also break early
+    fn gen_loop(&mut self, ty: &Ty, ctx: &Ctx, depth: usize) -> Expr {
+        let i = self.fresh("i");
+        let mut new_ctx = ctx.clone();
+        new_ctx.vars.push(Var { name: i.clone(), ty: Ty::Num, mutable: false });
+        let stmt_ty = self.ty(1);
+        let mut stmts = vec![self.expr(&stmt_ty, &new_ctx, depth - 1)];
+        if self.rng.gen_bool(0.3) {
+            let cond = self.expr(&Ty::Bool, &new_ctx, depth - 1);
+            let value = self.expr(ty, &new_ctx, depth - 1);
+            let early = mk(ExprKind::Break(Box::new(value)));
+            stmts.push(mk(ExprKind::If(Box::new(cond), Box::new(early), Box::new(num(0)))));
+        }
+        let incr = mk(ExprKind::UnOp(Op1::Add1, Box::new(var(&i))));
+        stmts.push(mk(ExprKind::Set(i.clone(), Box::new(incr))));
+        let bound = num(self.rng.gen_range(0..4));
+        let done = mk(ExprKind::BinOp(Op2::GreaterEqual, Box::new(var(&i)), Box::new(bound)));
+        let value = self.expr(ty, &new_ctx, depth - 1);
+        let body = mk(ExprKind::If(
+            Box::new(done),
+            Box::new(mk(ExprKind::Break(Box::new(value)))),
+            Box::new(mk(ExprKind::Block(stmts))),
+        ));
+        mk(ExprKind::Let(vec![(i, num(0))], Box::new(mk(ExprKind::Loop(Box::new(body))))))
+    }
+
+    fn gen_call(&mut self, ty: &Ty, ctx: &Ctx, depth: usize) -> Option<Expr> {
+        if !ctx.can_call {
+            return None;
+        }
+        let candidates: Vec<usize> = (0..self.sigs.len()).filter(|i| &self.sigs[*i].ret == ty).collect();
+        let sig = *candidates.choose(&mut self.rng)?;
+        let fuel = match &ctx.fuel {
+            Some(fuel) => mk(ExprKind::UnOp(Op1::Sub1, Box::new(var(fuel)))),
+            None => num(self.rng.gen_range(0..=3)),
+        };
+        let mut args = vec![fuel];
+        for ty in self.sigs[sig].params.clone() {
+            args.push(self.expr(&ty, ctx, depth - 1));
+        }
+        Some(mk(ExprKind::Call(self.sigs[sig].name.clone(), args)))
+    }
+
+    // Generates mutually recursive functions of the form
+    // (fun (f fuel ...) (if (<= fuel 0) base recursive))
+    // where only recursive calls other functions, with (sub1 fuel)
+    fn gen_fundefs(&mut self) -> Vec<FunDef> {
+        for i in 0..self.rng.gen_range(0..=3) {
+            let params = (0..self.rng.gen_range(0..=2)).map(|_| self.ty(1)).collect();
+            let ret = self.ty(1);
+            self.sigs.push(Sig { name: format!("f{}", i), params, ret });
+        }
+        let mut defs = vec![];
+        for i in 0..self.sigs.len() {
+            let fuel = self.fresh("fuel");
+            let mut vars = vec![Var { name: fuel.clone(), ty: Ty::Num, mutable: false }];
+            let mut params = vec![fuel.clone()];
+            for ty in self.sigs[i].params.clone() {
+                let name = self.fresh("p");
+                vars.push(Var { name: name.clone(), ty, mutable: true });
+                params.push(name);
+            }
+            let ret = self.sigs[i].ret.clone();
+            let base_ctx = Ctx { vars: vars.clone(), fuel: None, can_call: false };
+            let base = self.expr(&ret, &base_ctx, 2);
+            let rec_ctx = Ctx { vars, fuel: Some(fuel.clone()), can_call: true };
+            let rec = self.expr(&ret, &rec_ctx, 3);
+            let done = mk(ExprKind::BinOp(Op2::LessEqual, Box::new(var(&fuel)), Box::new(num(0))));
+            let body = mk(ExprKind::If(Box::new(done), Box::new(base), Box::new(rec)));
+            defs.push(FunDef { name: self.sigs[i].name.clone(), params, body, span: Span::default() });
+        }
+        defs
+    }
+
+    // Returns a program and the input to run it with
+    pub fn program(&mut self) -> (Program, String) {
+        let defs = self.gen_fundefs();
+        let input_ty = if self.rng.gen_bool(self.ill_typed * 4.0) { Ty::Bool } else { Ty::Num };
+        let input = match input_ty {
+            Ty::Bool => self.rng.gen::<bool>().to_string(),
+            _ => self.rng.gen_range(-10..=10).to_string(),
+        };
+        // input is typed as a number, so a boolean input makes the program ill-typed
+        let vars = vec![Var { name: "input".to_string(), ty: Ty::Num, mutable: false }];
+        let ctx = Ctx { vars, fuel: None, can_call: true };
+        let ty = self.ty(2);
+        let main = self.expr(&ty, &ctx, 4);
+        (Program { defs, main }, input)
+    }
+}
@@ -0,0 +1,3 @@
+mod generator;
+mod shrink;
+mod fuzz_tests;
@@ -0,0 +1,121 @@
+use snek::syntax::*;
+
+fn children_mut(e: &mut Expr) -> Vec<&mut Expr> {
+    match &mut e.kind {
+        ExprKind::Number(_) | ExprKind::Boolean(_) | ExprKind::Var(_) => vec![],
+        ExprKind::Let(bindings, body) => {
+            let mut children: Vec<&mut Expr> = bindings.iter_mut().map(|(_, e)| e).collect();
+            children.push(body);
+            children
+        }
+        ExprKind::UnOp(_, e)
+        | ExprKind::Loop(e)
+        | ExprKind::Break(e)
+        | ExprKind::Set(_, e)
+        | ExprKind::Print(e)
+        | ExprKind::TupLen(e)
+        | ExprKind::Lambda(_, e)
+        | ExprKind::Closure(_, e) => vec![e],
+        ExprKind::BinOp(_, e1, e2) | ExprKind::TupGet(e1, e2) => vec![e1, e2],
+        ExprKind::If(e1, e2, e3) | ExprKind::TupSet(e1, e2, e3) => vec![e1, e2, e3],
+        ExprKind::Block(es) | ExprKind::Tup(es) | ExprKind::Call(_, es) => es.iter_mut().collect(),
+        ExprKind::Apply(f, args) => {
+            let mut children = vec![&mut **f];
+            children.extend(args.iter_mut());
+            children
+        }
+    }
+}
+
+// Expressions one step smaller than e, smallest first
+// Some of them may not pass the checker, for example if they use a variable
+// whose binding was removed
+fn shrink_expr(e: &Expr) -> Vec<Expr> {
+    let mut candidates = vec![];
+    let mk = |kind| Expr::new(kind, e.span.clone());
+    match &e.kind {
+        ExprKind::Number(0) | ExprKind::Boolean(false) => {}
+        ExprKind::Number(_) | ExprKind::Boolean(_) | ExprKind::Var(_) => {
+            candidates.push(mk(ExprKind::Number(0)));
+            candidates.push(mk(ExprKind::Boolean(false)));
+        }
+        _ => {
+            candidates.push(mk(ExprKind::Number(0)));
+            candidates.push(mk(ExprKind::Boolean(false)));
+            let mut e = e.clone();
+            candidates.extend(children_mut(&mut e).into_iter().map(|child| child.clone()));
+        }
+    }
+    // Remove an element from lists whose length does not matter to the checker
+    match &e.kind {
+        ExprKind::Let(bindings, body) if bindings.len() > 1 => {
+            for i in 0..bindings.len() {
+                let mut bindings = bindings.clone();
+                bindings.remove(i);
+                candidates.push(mk(ExprKind::Let(bindings, body.clone())));
+            }
+        }
+        ExprKind::Block(es) if es.len() > 1 => {
+            for i in 0..es.len() {
+                let mut es = es.clone();
+                es.remove(i);
+                candidates.push(mk(ExprKind::Block(es)));
+            }
+        }
+        ExprKind::Tup(es) => {
+            for i in 0..es.len() {
+                let mut es = es.clone();
+                es.remove(i);
+                candidates.push(mk(ExprKind::Tup(es)));
+            }
+        }
+        _ => {}
+    }
+    // Shrink one of the children
+    let n_children = children_mut(&mut e.clone()).len();
+    for i in 0..n_children {
+        let child = children_mut(&mut e.clone())[i].clone();
+        for smaller in shrink_expr(&child) {
+            let mut e = e.clone();
+            *children_mut(&mut e)[i] = smaller;
+            candidates.push(e);
+        }
+    }
+    candidates
+}
+
+fn shrink_program(p: &Program) -> Vec<Program> {
+    let mut candidates = vec![];
+    for i in 0..p.defs.len() {
+        let mut p = p.clone();
+        p.defs.remove(i);
+        candidates.push(p);
+    }
+    for main in shrink_expr(&p.main) {
+        candidates.push(Program { defs: p.defs.clone(), main });
+    }
+    for i in 0..p.defs.len() {
+        for body in shrink_expr(&p.defs[i].body) {
+            let mut p = p.clone();
+            p.defs[i].body = body;
+            candidates.push(p);
+        }
+    }
+    candidates
+}
+
+// Minimizes a program for which fails returns true, keeping it failing
+// Repeatedly replaces the program with the first smaller one that passes
+// the checker and still fails, until there are none left
+pub fn shrink(p: Program, mut fails: impl FnMut(&Program) -> bool) -> Program {
+    let mut p = p;
+    'outer: loop {
+        for candidate in shrink_program(&p) {
+            if snek::check::check(&candidate).is_ok() && fails(&candidate) {
+                p = candidate;
+                continue 'outer;
+            }
+        }
+        return p;
+    }
+}
@@ -1,12 +1,19 @@
 use std::{
+    fs,
     path::{Path, PathBuf},
     process::Command,
+    thread,
 };
 
+use snek::{interp, jit};
+
+const STACK_SIZE: usize = 8 << 20;
+
 pub(crate) enum TestKind {
     Success,
     RuntimeError,
     StaticError,
+    Differential,
 }
 
 #[macro_export]
@@ -24,6 +31,34 @@ macro_rules! static_error_tests {
     ($($tt:tt)*) => { $crate::tests!(StaticError => $($tt)*); }
 }
 
+// Tests without an expected output: the compiled program must behave
+// like the reference interpreter on the same input
+#[macro_export]
+macro_rules! differential_tests {
+    (
+        $(
+            {
+                name: $name:ident,
+                file: $file:literal
+                $(, input: $input:literal)?
+                $(,)?
+            }
+        ),*
+        $(,)?
+    ) => {
+        $(
+            #[test]
+            fn $name() {
+                #[allow(unused_assignments, unused_mut)]
+                let mut input = None;
+                $(input = Some($input);)?
+                let kind = $crate::infra::TestKind::Differential;
+                $crate::infra::run_test(stringify!($name), $file, input, "", kind);
+            }
+        )*
+    };
+}
+
 #[macro_export]
 macro_rules! tests {
     ($kind:ident =>
@@ -60,17 +95,15 @@ pub(crate) fn run_test(
 ) {
     let file = Path::new("tests").join(file);
     match kind {
-        TestKind::Success => run_success_test(name, &file, expected, input),
-        TestKind::RuntimeError => run_runtime_error_test(name, &file, expected, input),
+        TestKind::Success => run_success_test(&file, expected, input),
+        TestKind::RuntimeError => run_runtime_error_test(&file, expected, input),
         TestKind::StaticError => run_static_error_test(name, &file, expected),
+        TestKind::Differential => run_differential_test(&file, input),
     }
 }
 
-fn run_success_test(name: &str, file: &Path, expected: &str, input: Option<&str>) {
-    if let Err(err) = compile(name, file) {
-        panic!("expected a successful compilation, but got an error: `{err}`");
-    }
-    match run(name, input) {
+fn run_success_test(file: &Path, expected: &str, input: Option<&str>) {
+    match run(file, input) {
         Err(err) => {
             panic!("expected a successful execution, but got an error: `{err}`");
         }
@@ -80,11 +113,8 @@ fn run_success_test(name: &str, file: &Path, expected: &str, input: Option<&str>
     }
 }
 
-fn run_runtime_error_test(name: &str, file: &Path, expected: &str, input: Option<&str>) {
-    if let Err(err) = compile(name, file) {
-        panic!("expected a successful compilation, but got an error: `{err}`");
-    }
-    match run(name, input) {
+fn run_runtime_error_test(file: &Path, expected: &str, input: Option<&str>) {
+    match run(file, input) {
         Ok(out) => {
             panic!("expected a runtime error, but program executed succesfully - expected error: `{expected}`, output: `{out}`");
         }
@@ -103,39 +133,83 @@ fn run_static_error_test(name: &str, file: &Path, expected: &str) {
     }
 }
 
-fn compile(name: &str, file: &Path) -> Result<(), String> {
-    // Run the compiler
+fn run_differential_test(file: &Path, input: Option<&str>) {
+    let (expected_out, expected_err) = interpret(file, input);
+    let (actual_out, actual_err) = run_with_stdout(file, input);
+    match (expected_err, actual_err) {
+        (None, None) => {}
+        (Some(expected), Some(err)) => check_error_msg(&err, &expected),
+        (None, Some(err)) => {
+            panic!("the interpreter succeeded, but the compiled program failed with `{err}`")
+        }
+        (Some(expected), None) => {
+            panic!("the interpreter failed with `{expected}`, but the compiled program succeeded")
+        }
+    }
+    diff(&expected_out, actual_out);
+}
+
+// Evaluates a program with the reference interpreter
+// Returns what it printed and the runtime error, if any
+fn interpret(file: &Path, input: Option<&str>) -> (String, Option<String>) {
+    let file_name = file.to_str().unwrap().to_string();
+    let contents = std::fs::read_to_string(file).expect("could not read the test file");
+    let input = interp::parse_input(input.unwrap_or("false")).expect("the input should be valid");
+    // Every call takes a few frames of the interpreter, so it gets the
+    // stack it needs to report stack overflows instead of running out
+    let thread = thread::Builder::new().stack_size(interp::STACK_SIZE).spawn(move || {
+        let prog = snek::parser::parse(&file_name, &contents).expect("the test file should parse");
+        snek::check::check(&prog).expect("the test file should pass the checker");
+        let mut out = vec![];
+        let result = interp::run(&prog, input, &mut out);
+        (String::from_utf8(out).unwrap().trim().to_string(), result.err().map(|err| err.to_string()))
+    });
+    thread.unwrap().join().expect("the interpreter should not panic")
+}
+
+pub(crate) fn compile(name: &str, file: &Path) -> Result<(), String> {
+    // Run the compiler, which also assembles and links
     let compiler: PathBuf = ["target", "debug", env!("CARGO_PKG_NAME")].iter().collect();
     let output = Command::new(&compiler)
+        .arg("build")
         .arg(file)
-        .arg(&mk_path(name, Ext::Asm))
+        .arg("-o")
+        .arg(mk_path(name, Ext::Run))
         .output()
         .expect("could not run the compiler");
     if !output.status.success() {
         return Err(String::from_utf8(output.stderr).unwrap());
     }
-
-    // Assemble and link
-    let output = Command::new("make")
-        .arg(&mk_path(name, Ext::Run))
-        .output()
-        .expect("could not run make");
-    assert!(output.status.success(), "linking failed");
-
     Ok(())
 }
 
-fn run(name: &str, input: Option<&str>) -> Result<String, String> {
-    let mut cmd = Command::new(&mk_path(name, Ext::Run));
-    if let Some(input) = input {
-        cmd.arg(input);
+fn run(file: &Path, input: Option<&str>) -> Result<String, String> {
+    match run_with_stdout(file, input) {
+        (stdout, None) => Ok(stdout),
+        (_, Some(err)) => Err(err),
     }
-    let output = cmd.output().unwrap();
-    if output.status.success() {
-        Ok(String::from_utf8(output.stdout).unwrap().trim().to_string())
-    } else {
-        Err(String::from_utf8(output.stderr).unwrap().trim().to_string())
+}
+
+// Compiles a test program and runs it in memory
+// Returns what it printed and the runtime error, if any
+fn run_with_stdout(file: &Path, input: Option<&str>) -> (String, Option<String>) {
+    let file_name = file.to_str().unwrap();
+    let contents = std::fs::read_to_string(file).expect("could not read the test file");
+    let prog = snek::parser::parse(file_name, &contents).unwrap_or_else(|diags| {
+        panic!("expected a successful compilation, but got an error: `{}`", diags[0])
+    });
+    if let Err(diags) = snek::check::check(&prog) {
+        panic!("expected a successful compilation, but got an error: `{}`", diags[0]);
     }
+    let asm = snek::compiler::compile_asm(&prog);
+    let input = interp::parse_input(input.unwrap_or("false")).expect("the input should be valid");
+    // Give the program as much stack as an executable gets
+    let thread = thread::Builder::new().stack_size(STACK_SIZE).spawn(move || {
+        let mut out = vec![];
+        let result = jit::run(&asm, input, &mut out);
+        (String::from_utf8(out).unwrap().trim().to_string(), result.err().map(|err| err.to_string()))
+    });
+    thread.unwrap().join().unwrap()
 }
 
 fn check_error_msg(found: &str, expected: &str) {
@@ -161,20 +235,30 @@ fn diff(expected: &str, found: String) {
     }
 }
 
+// Collects every .snek file under dir
+pub(crate) fn snek_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            snek_files(&path, files);
+        } else if path.extension().map_or(false, |ext| ext == "snek") {
+            files.push(path);
+        }
+    }
+}
+
 fn mk_path(name: &str, ext: Ext) -> PathBuf {
     Path::new("tests").join(format!("{name}.{ext}"))
 }
 
 #[derive(Copy, Clone)]
 enum Ext {
-    Asm,
     Run,
 }
 
 impl std::fmt::Display for Ext {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Ext::Asm => write!(f, "s"),
             Ext::Run => write!(f, "run"),
         }
     }
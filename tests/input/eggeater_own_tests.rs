@@ -52,19 +52,238 @@ success_tests! {
         file: "input/print_tuple_cycle.snek",
         expected: "(1, (2, (...)))",
     },
+    {
+        name: gc_garbage,
+        file: "input/gc_garbage.snek",
+        expected: "(44999850000, 299999)",
+    },
+    {
+        name: gc_cycle,
+        file: "input/gc_cycle.snek",
+        expected: "(2, (1, (...)))\n(1, (2, (...)))",
+    },
+    {
+        name: gc_list,
+        file: "input/gc_list.snek",
+        expected: "(200200000, 55)",
+    },
+    {
+        name: tail_calls,
+        file: "input/tail_calls.snek",
+        input: "10000000",
+        expected: "23416728348467685\n10000000\n10000000",
+    },
+    {
+        name: closures,
+        file: "input/closures.snek",
+        input: "4",
+        expected: "<function>\n(4, 5)\n8\n42\n(5, 6)\n0\n16",
+    },
+    {
+        name: gc_closures,
+        file: "input/gc_closures.snek",
+        expected: "(900000, 101)",
+    },
+    {
+        name: fun_values,
+        file: "input/fun_values.snek",
+        input: "3",
+        expected: "<function>\n(2, 4, 6)\n(6, 24, 120)\n11\n6\n3072",
+    },
 }
 
 runtime_error_tests! {
     {
         name: index_invalid_tuple,
         file: "input/index_invalid_tuple.snek",
-        expected: "tuple",
+        expected: "tup-get expected a tuple but got false",
     },
     {
         name: index_invalid_index,
         file: "input/index_invalid_index.snek",
-        expected: "invalid",
+        expected: "tup-get expected a number but got false",
+    },
+    {
+        name: arith_site,
+        file: "input/arith_site.snek",
+        expected: "error 2 at tests/input/arith_site.snek:4:5: + expected a number but got (1, 2)",
+    },
+    {
+        name: equal_mixed,
+        file: "input/equal_mixed.snek",
+        expected: "error 1 at tests/input/equal_mixed.snek:2:3: = expected operands of the same type but got 1 and true",
+    },
+    {
+        name: out_of_memory,
+        file: "input/out_of_memory.snek",
+        expected: "error 5 at tests/input/out_of_memory.snek:4:17: out of memory",
+    },
+    {
+        name: stack_overflow,
+        file: "input/stack_overflow.snek",
+        expected: "error 6 at tests/input/stack_overflow.snek:2:1: stack overflow",
+    },
+    {
+        name: index_out_of_bounds,
+        file: "input/index_out_of_bounds.snek",
+        input: "3",
+        expected: "error 7 at tests/input/index_out_of_bounds.snek:2:5: index 3 out of bounds for tuple of length 3",
+    },
+    {
+        name: set_out_of_bounds,
+        file: "input/set_out_of_bounds.snek",
+        input: "-1",
+        expected: "error 7 at tests/input/set_out_of_bounds.snek:3:9: index -1 out of bounds for tuple of length 3",
+    },
+    {
+        name: call_non_function,
+        file: "input/call_non_function.snek",
+        expected: "error 8 at tests/input/call_non_function.snek:2:5: expected a function but got (1, 2)",
+    },
+    {
+        name: arity_mismatch,
+        file: "input/arity_mismatch.snek",
+        input: "3",
+        expected: "error 9 at tests/input/arity_mismatch.snek:4:9: function expects 2 arguments, but got 1",
+    },
+    {
+        name: fun_arity_mismatch,
+        file: "input/fun_arity_mismatch.snek",
+        expected: "error 9 at tests/input/fun_arity_mismatch.snek:2:21: function expects 1 arguments, but got 2",
+    },
+}
+
+static_error_tests! {
+    {
+        name: invalid_binding,
+        file: "input/invalid_binding.snek",
+        expected: "input/invalid_binding.snek:2:7: error: Invalid binding: (y)",
+    },
+    {
+        name: multiple_errors,
+        file: "input/multiple_errors.snek",
+        expected: "multiple_errors.snek:2:1: error: Function name must be unique: f
+tests/input/multiple_errors.snek:3:13: error: Duplicate binding: a
+tests/input/multiple_errors.snek:4:9: error: Invalid block: (block)",
+    },
+    {
+        name: extra_forms,
+        file: "input/extra_forms.snek",
+        expected: "extra_forms.snek:3:1: error: Invalid program: unexpected form after the main expression
+tests/input/extra_forms.snek:4:1: error: Invalid program: unexpected form after the main expression
+tests/input/extra_forms.snek:4:13: error: Duplicate binding: a",
+    },
+    {
+        name: unbound_ids,
+        file: "input/unbound_ids.snek",
+        expected: "unbound_ids.snek:1:21: error: Unbound variable identifier c
+tests/input/unbound_ids.snek:3:5: error: Unbound variable identifier w",
+    },
+    {
+        name: call_errors,
+        file: "input/call_errors.snek",
+        expected: "call_errors.snek:1:19: error: Invalid use of input inside function f
+tests/input/call_errors.snek:2:10: error: Invalid break: break outside of loop
+tests/input/call_errors.snek:4:3: error: Invalid call: f expects 2 arguments, but got 1
+tests/input/call_errors.snek:5:3: error: Invalid call: undefined function h
+tests/input/call_errors.snek:6:3: error: Invalid break: break outside of loop",
+    },
+    {
+        name: lambda_errors,
+        file: "input/lambda_errors.snek",
+        expected: "lambda_errors.snek:1:18: error: Lambda parameters must be unique: (x x)
+tests/input/lambda_errors.snek:2:21: error: All lambda parameters must be identifiers: (y 1)",
     },
 }
 
-static_error_tests! {}
+differential_tests! {
+    {
+        name: diff_let_clobber,
+        file: "input/let_clobber.snek",
+    },
+    {
+        name: diff_type_tests,
+        file: "input/type_tests.snek",
+    },
+    {
+        name: diff_nested_calls,
+        file: "input/nested_calls.snek",
+        input: "4",
+    },
+    {
+        name: diff_points,
+        file: "input/points.snek",
+        input: "7",
+    },
+    {
+        name: diff_iterate,
+        file: "input/iterate.snek",
+    },
+    {
+        name: diff_print_tuple_cycle,
+        file: "input/print_tuple_cycle.snek",
+    },
+    {
+        name: diff_gc_list,
+        file: "input/gc_list.snek",
+    },
+    {
+        name: diff_deep_sum,
+        file: "input/deep_sum.snek",
+        input: "1000",
+    },
+    {
+        name: diff_stack_overflow,
+        file: "input/stack_overflow.snek",
+    },
+    {
+        name: diff_arith_site,
+        file: "input/arith_site.snek",
+    },
+    {
+        name: diff_equal_mixed,
+        file: "input/equal_mixed.snek",
+    },
+    {
+        name: diff_index_out_of_bounds,
+        file: "input/index_out_of_bounds.snek",
+        input: "-4",
+    },
+    {
+        name: diff_set_out_of_bounds,
+        file: "input/set_out_of_bounds.snek",
+        input: "2",
+    },
+    {
+        name: diff_index_invalid_tuple,
+        file: "input/index_invalid_tuple.snek",
+    },
+    {
+        name: diff_tail_calls,
+        file: "input/tail_calls.snek",
+        input: "5",
+    },
+    {
+        name: diff_closures,
+        file: "input/closures.snek",
+        input: "-7",
+    },
+    {
+        name: diff_call_non_function,
+        file: "input/call_non_function.snek",
+    },
+    {
+        name: diff_arity_mismatch,
+        file: "input/arity_mismatch.snek",
+        input: "1",
+    },
+    {
+        name: diff_fun_values,
+        file: "input/fun_values.snek",
+        input: "7",
+    },
+    {
+        name: diff_fun_arity_mismatch,
+        file: "input/fun_arity_mismatch.snek",
+    },
+}
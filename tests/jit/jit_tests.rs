@@ -0,0 +1,41 @@
+use snek::check::check;
+use snek::compiler::compile_asm;
+use snek::errors::SnekError;
+use snek::interp::{RuntimeError, Val};
+use snek::jit::run;
+use snek::parser::parse;
+
+fn jit(source: &str, input: Val) -> (String, Result<(), RuntimeError>) {
+    let prog = parse("jit.snek", source).unwrap();
+    check(&prog).unwrap();
+    let mut out = vec![];
+    let result = run(&compile_asm(&prog), input, &mut out);
+    (String::from_utf8(out).unwrap(), result)
+}
+
+#[test]
+fn prints_and_returns() {
+    let (out, result) = jit("(block (print (tup 1 true)) (* input 2))", Val::Num(21));
+    assert!(result.is_ok());
+    assert_eq!(out, "(1, true)\n42\n");
+}
+
+// An error deep in the compiled code comes back to the caller, which can go on
+// to run other programs
+#[test]
+fn errors_return_to_the_caller() {
+    let source = "
+(fun (down n) (if (= n 0) (+ n true) (down (sub1 n))))
+(block (print 1) (down input))";
+    for n in [0, 10, 1000] {
+        let (out, result) = jit(source, Val::Num(n));
+        assert_eq!(out, "1\n");
+        match result {
+            Err(RuntimeError::Snek { error: SnekError::InvalidArith, site, .. }) => assert_eq!(site.to_string(), "jit.snek:2:27"),
+            other => panic!("expected an arithmetic error, got {other:?}"),
+        }
+    }
+    let (out, result) = jit("(+ input 1)", Val::Num(1));
+    assert!(result.is_ok());
+    assert_eq!(out, "2\n");
+}
@@ -0,0 +1 @@
+mod jit_tests;
@@ -0,0 +1 @@
+mod repl_tests;
@@ -0,0 +1,79 @@
+use snek::repl;
+
+fn session(input: &str) -> String {
+    let mut out = vec![];
+    repl::run(input.as_bytes(), &mut out, false).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn definitions_persist() {
+    let out = session(
+        "(fun (double x) (* x 2))
+(double 21)
+(fun (quad x)
+  (double (double x)))
+(print (tup (quad 1) false))
+(double true)
+",
+    );
+    assert_eq!(
+        out,
+        "defined double
+42
+defined quad
+(4, false)
+(4, false)
+error 2 at <repl>:1:17: * expected a number but got true
+"
+    );
+}
+
+#[test]
+fn redefinitions_are_checked() {
+    let out = session(
+        "(fun (f x) x)
+(fun (g) (f 1))
+(fun (f x y) x)
+(g)
+(fun (f x) (+ x 1))
+(g)
+:defs
+",
+    );
+    assert_eq!(
+        out,
+        "defined f
+defined g
+<repl>:1:10: error: Invalid call: f expects 2 arguments, but got 1
+1
+defined f
+2
+(fun (f x) (+ x 1))
+(fun (g) (f 1))
+"
+    );
+}
+
+#[test]
+fn commands() {
+    let out = session(":asm (add1 input)\n:ast x\n:nope\n:quit\n1\n");
+    assert!(out.starts_with("our_code_starts_here:\n%line 1+0 <repl>\npush rbp\nmov rbp, rsp\n"), "{out}");
+    assert!(out.contains("mov r13, rsp\nmov rax, rdi\n"), "{out}");
+    assert!(!out.contains("snek_error_handler:"), "{out}");
+    assert!(out.contains("kind: Var(\n        \"x\",\n    ),"), "{out}");
+    assert!(out.contains("unknown command :nope"), "{out}");
+    assert!(!out.ends_with("1\n"), "{out}");
+}
+
+#[test]
+fn errors_do_not_end_the_session() {
+    let out = session("(+ 1\n   2)\n(let (x) x)\n(+ 1 2\n");
+    assert_eq!(
+        out,
+        "3
+<repl>:1:7: error: Invalid binding: x
+<repl>:1:1: error: Invalid expression: unclosed '('
+"
+    );
+}